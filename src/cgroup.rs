@@ -0,0 +1,166 @@
+use crate::config::Task;
+use crate::log::Event;
+use crate::sys;
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command as Subprocess;
+use std::thread;
+use std::time::Duration;
+
+// Control group v2 of a single run of a task, created under `cgroup_root`.
+pub struct Cgroup {
+    path: PathBuf,
+    // Path of cgroup.procs, prepared for writing between fork and exec
+    procs: CString,
+}
+
+// Resource usage of a run, unknown values are `None`.
+pub struct Usage {
+    peak_memory: Option<u64>,
+    cpu_time: Option<Duration>,
+}
+
+impl Cgroup {
+    // Creates a cgroup for the run with the task's limits.
+    pub fn create(root: &Path, task: &Task, run: u64) -> Result<Cgroup> {
+        enable_controllers(root, task)?;
+
+        // `/` can't be in a name, a leftover cgroup of an earlier run with the same id is reused
+        let path = root.join(format!("{}.{}", task.name.replace('/', "_"), run));
+        match fs::create_dir(&path) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                return Err(e).with_context(|| format!("cannot create cgroup {:?}", path));
+            }
+            _ => {}
+        }
+        let setup = || -> Result<CString> {
+            let limits = [
+                ("memory.max", task.memory_max.map(|max| max.to_string())),
+                ("cpu.max", task.cpu_max.map(|cpus| format!("{} 100000", (cpus * 100000.0).ceil() as u64))),
+                ("pids.max", task.pids_max.map(|max| max.to_string())),
+            ];
+            for (file, value) in limits.iter() {
+                if let Some(value) = value {
+                    fs::write(path.join(file), value)
+                        .with_context(|| format!("cannot set {} of cgroup {:?}", file, path))?;
+                }
+            }
+            Ok(CString::new(path.join("cgroup.procs").as_os_str().as_bytes())?)
+        };
+        match setup() {
+            Ok(procs) => Ok(Cgroup { path, procs }),
+            Err(e) => {
+                // Nothing joined the cgroup yet, so it can be removed right away
+                let _ = fs::remove_dir(&path);
+                Err(e)
+            }
+        }
+    }
+
+    // Makes the child of the command join the cgroup before exec.  Only root can move processes
+    // between cgroups it doesn't own, so this has to be the first hook, before privileges are
+    // dropped.
+    pub fn join(&self, command: &mut Subprocess) {
+        // Writing 0 to cgroup.procs moves the writing process
+        let procs = self.procs.clone();
+        unsafe {
+            command.pre_exec(move || sys::write_file(&procs, b"0"));
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let peak_memory = fs::read_to_string(self.path.join("memory.peak"))
+            .ok()
+            .and_then(|peak| peak.trim().parse().ok());
+        let cpu_time = fs::read_to_string(self.path.join("cpu.stat"))
+            .ok()
+            .and_then(|stat| {
+                stat.lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))
+                    .and_then(|usec| usec.parse().ok())
+            })
+            .map(Duration::from_micros);
+        Usage { peak_memory, cpu_time }
+    }
+
+    // Kills any processes left in the cgroup and removes it.
+    pub fn remove(self, name: &str) -> Result<()> {
+        let procs = self.procs()?;
+        if !procs.is_empty() {
+            Event::warn(format!("killing {} process(es) left in the cgroup", procs.len()))
+                .task(name)
+                .emit();
+            // cgroup.kill is available since Linux 5.14, kill the processes one by one otherwise
+            if fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+                for pid in procs.iter() {
+                    crate::task::signal(*pid, libc::SIGKILL);
+                }
+            }
+        }
+
+        // Killed processes leave the cgroup asynchronously, it can't be removed until they do
+        for _ in 0..100 {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    // Processes forked meanwhile escaped the kill above
+                    for pid in self.procs()?.iter() {
+                        crate::task::signal(*pid, libc::SIGKILL);
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e).with_context(|| format!("cannot remove cgroup {:?}", self.path)),
+            }
+        }
+        bail!("cannot remove cgroup {:?}, it's still in use", self.path);
+    }
+
+    fn procs(&self) -> Result<Vec<u32>> {
+        let procs = fs::read_to_string(self.path.join("cgroup.procs"))
+            .with_context(|| format!("cannot read processes of cgroup {:?}", self.path))?;
+        Ok(procs.lines().filter_map(|pid| pid.parse().ok()).collect())
+    }
+}
+
+// Enables controllers for the task's limits in `cgroup_root`, memory accounting is enabled when
+// possible to report the peak memory usage.
+fn enable_controllers(root: &Path, task: &Task) -> Result<()> {
+    let subtree_control = root.join("cgroup.subtree_control");
+    let enabled = fs::read_to_string(&subtree_control)
+        .with_context(|| format!("cannot read {:?}", subtree_control))?;
+    let controllers = [
+        ("memory", task.memory_max.is_some(), true),
+        ("cpu", task.cpu_max.is_some(), false),
+        ("pids", task.pids_max.is_some(), false),
+    ];
+    for &(controller, required, wanted) in controllers.iter() {
+        if !(required || wanted) || enabled.split_whitespace().any(|c| c == controller) {
+            continue;
+        }
+        match fs::write(&subtree_control, format!("+{}", controller)) {
+            Err(e) if required => {
+                return Err(e).with_context(|| format!("cannot enable the {} controller in {:?}", controller, root));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(peak) = self.peak_memory {
+            write!(f, ", peak memory {:.1} MiB", peak as f64 / (1 << 20) as f64)?;
+        }
+        if let Some(cpu_time) = self.cpu_time {
+            write!(f, ", CPU time {:.2}s", cpu_time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::{Command, Config, EnvVal, Task};
+use anyhow::{bail, Result};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Checks a config for problems which parsing doesn't catch and which would otherwise show up only
+// when a task is due, like timings which never match or missing executables.  All problems are
+// printed, including the invalid options and tasks parsing would stop at, and the check fails
+// when there is any.
+pub fn check(path: &str) -> Result<()> {
+    let (config, problems) = Config::read_file_all(path)?;
+
+    let problems = problems.iter()
+        .map(|problem| format!("{:#}", problem))
+        .chain(config.tasks.iter().flat_map(|task| {
+            check_task(task)
+                .into_iter()
+                .map(move |problem| format!("[{}] {}", task.name, problem))
+        }))
+        .collect::<Vec<_>>();
+
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            eprintln!("{}", problem);
+        }
+        bail!("found {} problem(s) in config file {:?}", problems.len(), path);
+    }
+
+    eprintln!("config file {:?} is ok, {} task(s)", path, config.tasks.len());
+    Ok(())
+}
+
+fn check_task(task: &Task) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(e) = task.next_run() {
+        problems.push(format!("timing never matches: {:#}", e));
+    }
+
+    // The executable is looked up using the task's PATH if it sets one
+    let path_var = match task.env.get("PATH") {
+        Some(EnvVal::Set(path)) => Some(OsString::from(path)),
+        _ => env::var_os("PATH"),
+    };
+    match &task.command {
+        Command::Shell(_) => {
+            if !is_in_path(&task.shell, path_var.as_ref()) {
+                problems.push(format!("shell `{}` not found or not executable", task.shell));
+            }
+        }
+        Command::Argv(args) => {
+            if !is_in_path(&args[0], path_var.as_ref()) {
+                problems.push(format!("executable `{}` not found or not executable", args[0]));
+            }
+        }
+    }
+
+    problems
+}
+
+fn is_in_path(program: &str, path_var: Option<&OsString>) -> bool {
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+    path_var.is_some_and(|path_var| {
+        env::split_paths(path_var).any(|dir| is_executable(&dir.join(program)))
+    })
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
@@ -1,11 +1,15 @@
-use anyhow::{anyhow, bail, Context, Result};
+use crate::log;
+use crate::sys::{self, Passwd};
+use crate::zone::Zone;
+use anyhow::{anyhow, bail, Context, Error, Result};
 use chrono::{Duration, Weekday};
 use std::collections::HashMap as Map;
 use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::fs;
+use std::net::SocketAddr;
 use std::ops::Range;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use toml::value::{Table, Value};
 
 #[derive(Debug)]
@@ -14,34 +18,189 @@ pub struct Config {
     pub env: Map<String, EnvVal>,
     pub clear_env: bool,
     pub on_startup: bool,
+    pub kill_grace: Duration,
+    pub overlap: Overlap,
+    pub catch_up: bool,
+    pub state_file: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub timezone: Zone,
+    pub dst: Dst,
+    pub stdout: Output,
+    pub stderr: Output,
+    pub workdir: Option<PathBuf>,
+    pub umask: Option<u32>,
+    pub stdin: Input,
+    pub cgroup_root: Option<PathBuf>,
+    pub kill_leftovers: bool,
+    pub shutdown_signal: libc::c_int,
+    pub shutdown_timeout: Duration,
+    pub log_format: log::Format,
+    pub log_level: log::Level,
+    pub log_target: log::Target,
     pub debug: bool,
     pub tasks: Vec<Task>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Task {
     pub name: String,
     pub command: Command,
-    pub time: Time,
+    // `None` for tasks which run only when triggered
+    pub time: Option<Time>,
     pub shell: String,
     pub env: Map<String, EnvVal>,
     pub clear_env: bool,
     pub on_startup: bool,
+    pub timeout: Option<Duration>,
+    pub kill_grace: Duration,
+    pub overlap: Overlap,
+    pub catch_up: bool,
+    pub timezone: Zone,
+    pub dst: Dst,
+    pub stdout: Output,
+    pub stderr: Output,
+    pub retry: Option<Retry>,
+    // Tasks to run after a run succeeds or fails
+    pub on_success: Vec<String>,
+    pub on_failure: Vec<String>,
+    // Credentials the task runs with, `None` keeps the ones of ocron
+    pub user: Option<Passwd>,
+    pub group: Option<u32>,
+    pub supplementary_groups: Option<Vec<u32>>,
+    pub workdir: Option<PathBuf>,
+    pub umask: Option<u32>,
+    pub stdin: Input,
+    pub nice: Option<i32>,
+    pub ioprio: Option<Ioprio>,
+    pub rlimits: Vec<Rlimit>,
+    // Copied from the global option, runs get their own cgroups under it when set
+    pub cgroup_root: Option<PathBuf>,
+    pub memory_max: Option<u64>,
+    // Number of CPUs, may be fractional
+    pub cpu_max: Option<f64>,
+    pub pids_max: Option<u64>,
+    pub kill_leftovers: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Command {
     Shell(String),
     Argv(Vec<String>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum EnvVal {
     Clear,
     Set(String),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlap {
+    Allow,
+    Skip,
+    Queue,
+    Replace,
+}
+
+// How `on` timings treat local times affected by daylight saving time transitions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dst {
+    pub gap: DstGap,
+    pub repeat: DstRepeat,
+}
+
+// Local times which don't exist because the clocks were set forward.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DstGap {
+    // Don't run at all
+    Skip,
+    // Run at the first instant after the gap
+    Next,
+}
+
+// Local times which happen twice because the clocks were set back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DstRepeat {
+    // Run only at the first occurrence
+    First,
+    // Run at both occurrences
+    Both,
+}
+
+// Retrying of failed runs, the delay before each retry is `delay * backoff^(attempt - 1)`, at
+// most `max_delay`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retry {
+    // Maximum number of runs including the first one
+    pub attempts: u32,
+    pub delay: Duration,
+    pub backoff: f64,
+    pub max_delay: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ioprio {
+    pub class: IoprioClass,
+    // 0 (highest) to 7 (lowest), unused by the idle class
+    pub level: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoprioClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rlimit {
+    pub resource: Resource,
+    // `None` is unlimited
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resource {
+    Nofile,
+    As,
+    Cpu,
+    Core,
+    Nproc,
+    Fsize,
+}
+
+// What the task gets on its stdin.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Inherit,
+    Null,
+    File(PathBuf),
+    Text(String),
+}
+
+// Where the stdout or stderr of a task goes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Inherit,
+    Null,
+    // Forwarded to ocron's own output, each line prefixed with the task name
+    Log,
+    File(OutputFile),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputFile {
+    // Path template, `{name}` and `{date}` are replaced when a run starts
+    pub path: String,
+    // Rotate the file once it would grow over this size in bytes
+    pub max_size: Option<u64>,
+    // Number of rotated files to keep
+    pub keep: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Time {
     On {
         second: Vec<u32>,
@@ -61,59 +220,222 @@ pub enum Time {
 
 impl Config {
     pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let (config, problems) = Config::read_file_all(path)?;
+        match problems.into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(config),
+        }
+    }
+
+    // Like `read_file`, but invalid options and tasks are skipped instead of failing on the first
+    // one, and every problem found is returned along with the rest of the config.
+    pub fn read_file_all<P: AsRef<Path>>(path: P) -> Result<(Config, Vec<Error>)> {
         let path = path.as_ref();
         let config_data = fs::read(path)
             .with_context(|| format!("cannot read config file {:?}", path))?;
         let config = toml::from_slice::<Table>(&config_data)
             .context("parsing toml")?;
-        parse_config(config)
+        Ok(parse_config(config))
+    }
+
+    #[cfg(test)]
+    pub fn parse(toml: &str) -> Result<Config> {
+        let (config, problems) = parse_config(toml::from_str::<Table>(toml)?);
+        match problems.into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(config),
+        }
     }
 }
 
-fn parse_config(table: Table) -> Result<Config> {
+fn parse_config(mut table: Table) -> (Config, Vec<Error>) {
     let mut config = Config {
         shell: String::from("/bin/sh"),
         env: Map::new(),
         clear_env: false,
         on_startup: false,
+        kill_grace: Duration::seconds(10),
+        overlap: Overlap::Allow,
+        catch_up: false,
+        state_file: None,
+        control_socket: None,
+        metrics_listen: None,
+        timezone: Zone::local(),
+        dst: Dst { gap: DstGap::Next, repeat: DstRepeat::First },
+        stdout: Output::Inherit,
+        stderr: Output::Inherit,
+        workdir: None,
+        umask: None,
+        stdin: Input::Inherit,
+        cgroup_root: None,
+        kill_leftovers: false,
+        shutdown_signal: libc::SIGTERM,
+        shutdown_timeout: Duration::seconds(30),
+        log_format: log::Format::Text,
+        log_level: log::Level::Info,
+        log_target: log::Target::Stderr,
         debug: false,
         tasks: Vec::new(),
     };
 
-    for (key, value) in table.into_iter() {
-        match key.as_str() {
-            "shell" => {
-                config.shell = parse_string(value)
-                    .context("parsing global `shell`")?;
-            }
-            "env" => {
-                config.env.extend(
-                    parse_env(value)
-                        .context("parsing global `env`")?
-                );
-            }
-            "clear_env" => {
-                config.clear_env = parse_bool(value)
-                    .context("parsing global `clear_env`")?;
-            }
-            "on_startup" => {
-                config.on_startup = parse_bool(value)
-                    .context("parsing global `on_startup`")?;
-            }
-            "debug" => {
-                config.debug = parse_bool(value)
-                    .context("parsing global `debug`")?;
-            }
-            "task" => {
-                parse_tasks(value, &mut config)
-                    .context("parsing tasks")?;
-            }
-            _ => bail!("unknown option `{}`, valid options are `shell`, `env`, `clear_env`, `on_startup`, \
-                       `debug` and `task`.", key),
+    // Tasks are parsed last, so that they see all the global defaults
+    let tasks = table.remove("task");
+
+    let mut problems = Vec::new();
+    for (key, value) in table.into_iter().chain(tasks.map(|tasks| (String::from("task"), tasks))) {
+        if let Err(e) = parse_global(&key, value, &mut config, &mut problems) {
+            problems.push(e);
         }
     }
 
-    Ok(config)
+    if config.state_file.is_none() {
+        for task in config.tasks.iter().filter(|task| task.catch_up) {
+            problems.push(anyhow!(
+                "task `{}` has `catch_up` set, which requires the global `state_file` option", task.name,
+            ));
+        }
+    }
+
+    (config, problems)
+}
+
+// Parses a global option, or the tasks, whose problems are added to `problems`.
+fn parse_global(key: &str, value: Value, config: &mut Config, problems: &mut Vec<Error>) -> Result<()> {
+    match key {
+        "shell" => {
+            config.shell = parse_string(value)
+                .context("parsing global `shell`")?;
+        }
+        "env" => {
+            config.env.extend(
+                parse_env(value)
+                    .context("parsing global `env`")?
+            );
+        }
+        "clear_env" => {
+            config.clear_env = parse_bool(value)
+                .context("parsing global `clear_env`")?;
+        }
+        "on_startup" => {
+            config.on_startup = parse_bool(value)
+                .context("parsing global `on_startup`")?;
+        }
+        "kill_grace" => {
+            config.kill_grace = parse_table(value)
+                .and_then(parse_duration)
+                .context("parsing global `kill_grace`")?;
+        }
+        "overlap" => {
+            config.overlap = parse_overlap(value)
+                .context("parsing global `overlap`")?;
+        }
+        "catch_up" => {
+            config.catch_up = parse_bool(value)
+                .context("parsing global `catch_up`")?;
+        }
+        "state_file" => {
+            config.state_file = Some(
+                parse_string(value)
+                    .context("parsing global `state_file`")?
+                    .into()
+            );
+        }
+        "control_socket" => {
+            config.control_socket = Some(
+                parse_string(value)
+                    .context("parsing global `control_socket`")?
+                    .into()
+            );
+        }
+        "metrics_listen" => {
+            let addr = parse_string(value)
+                .context("parsing global `metrics_listen`")?;
+            config.metrics_listen = Some(addr.parse()
+                .with_context(|| format!("parsing global `metrics_listen`, invalid address `{}`", addr))?);
+        }
+        "timezone" => {
+            config.timezone = parse_timezone(value)
+                .context("parsing global `timezone`")?;
+        }
+        "dst_gap" => {
+            config.dst.gap = parse_dst_gap(value)
+                .context("parsing global `dst_gap`")?;
+        }
+        "dst_repeat" => {
+            config.dst.repeat = parse_dst_repeat(value)
+                .context("parsing global `dst_repeat`")?;
+        }
+        "stdout" => {
+            config.stdout = parse_output(value)
+                .context("parsing global `stdout`")?;
+        }
+        "stderr" => {
+            config.stderr = parse_output(value)
+                .context("parsing global `stderr`")?;
+        }
+        "workdir" => {
+            config.workdir = Some(
+                parse_workdir(value)
+                    .context("parsing global `workdir`")?
+            );
+        }
+        "umask" => {
+            config.umask = Some(
+                parse_umask(value)
+                    .context("parsing global `umask`")?
+            );
+        }
+        "stdin" => {
+            config.stdin = parse_input(value)
+                .context("parsing global `stdin`")?;
+        }
+        "cgroup_root" => {
+            config.cgroup_root = Some(
+                parse_cgroup_root(value)
+                    .context("parsing global `cgroup_root`")?
+            );
+        }
+        "kill_leftovers" => {
+            config.kill_leftovers = parse_bool(value)
+                .context("parsing global `kill_leftovers`")?;
+        }
+        "shutdown_signal" => {
+            config.shutdown_signal = parse_signal(value)
+                .context("parsing global `shutdown_signal`")?;
+        }
+        "shutdown_timeout" => {
+            config.shutdown_timeout = parse_table(value)
+                .and_then(parse_duration)
+                .context("parsing global `shutdown_timeout`")?;
+        }
+        "log_format" => {
+            config.log_format = parse_log_format(value)
+                .context("parsing global `log_format`")?;
+        }
+        "log_level" => {
+            config.log_level = parse_log_level(value)
+                .context("parsing global `log_level`")?;
+        }
+        "log_target" => {
+            config.log_target = parse_log_target(value)
+                .context("parsing global `log_target`")?;
+        }
+        "debug" => {
+            config.debug = parse_bool(value)
+                .context("parsing global `debug`")?;
+        }
+        "task" => {
+            let task_problems = parse_tasks(value, config)
+                .context("parsing tasks")?;
+            problems.extend(task_problems.into_iter().map(|e| e.context("parsing tasks")));
+        }
+        _ => bail!("unknown option `{}`, valid options are `shell`, `env`, `clear_env`, `on_startup`, \
+                   `kill_grace`, `overlap`, `catch_up`, `state_file`, `control_socket`, `metrics_listen`, \
+                   `timezone`, `dst_gap`, `dst_repeat`, `stdout`, `stderr`, `workdir`, `umask`, `stdin`, \
+                   `cgroup_root`, `kill_leftovers`, `shutdown_signal`, `shutdown_timeout`, `log_format`, \
+                   `log_level`, `log_target`, `debug` and `task`.", key),
+    }
+    Ok(())
 }
 
 fn parse_string(value: Value) -> Result<String> {
@@ -144,6 +466,14 @@ fn parse_integer(value: Value) -> Result<i64> {
     }
 }
 
+fn parse_float(value: Value) -> Result<f64> {
+    match value {
+        Value::Float(float) => Ok(float),
+        Value::Integer(int) => Ok(int as f64),
+        _ => bail!("expected number, found `{:?}`", value),
+    }
+}
+
 fn parse_env(value: Value) -> Result<Map<String, EnvVal>> {
     let table = parse_table(value)?;
     table.into_iter().map(|(key, value)| {
@@ -157,28 +487,85 @@ fn parse_env(value: Value) -> Result<Map<String, EnvVal>> {
     .collect()
 }
 
-fn parse_tasks(value: Value, config: &mut Config) -> Result<()> {
+// Parses the tasks which are valid into `config` and returns the problems of the others.
+fn parse_tasks(value: Value, config: &mut Config) -> Result<Vec<Error>> {
+    let tasks = match value {
+        Value::Array(tasks) => tasks,
+        _ => bail!("option `task` must be an array, try using \"[[task]]\", found {:?}", value),
+    };
+    let mut problems = Vec::new();
+    for value in tasks.into_iter() {
+        if let Err(e) = add_task(value, config) {
+            problems.push(e);
+        }
+    }
+
+    // Chains to the skipped tasks would look broken
+    if problems.is_empty() {
+        if let Err(e) = check_chains(&config.tasks) {
+            problems.push(e);
+        }
+    }
+    Ok(problems)
+}
+
+fn add_task(value: Value, config: &mut Config) -> Result<()> {
     match value {
-        Value::Array(tasks) => {
-            for value in tasks.into_iter() {
-                match value {
-                    Value::Table(table) => {
-                        if let Some(Value::String(name)) = table.get("name") {
-                            let name = name.clone();
-                            let task = parse_task(name.clone(), table, &config)
-                                .with_context(|| format!("parsing task `{}`", name))?;
-                            config.tasks.push(task);
-                        } else {
-                            bail!("missing `name` for task");
-                        }
-                    }
-                    _ => bail!("task must be a table, found {:?}", value),
+        Value::Table(table) => {
+            if let Some(Value::String(name)) = table.get("name") {
+                let name = name.clone();
+                if config.tasks.iter().any(|task| task.name == name) {
+                    bail!("duplicate task name `{}`, task names have to be unique", name);
                 }
+                let task = parse_task(name.clone(), table, config)
+                    .with_context(|| format!("parsing task `{}`", name))?;
+                config.tasks.push(task);
+            } else {
+                bail!("missing `name` for task");
             }
         }
-        _ => bail!("option `task` must be an array, try using \"[[task]]\", found {:?}", value),
+        _ => bail!("task must be a table, found {:?}", value),
+    }
+    Ok(())
+}
+
+// Checks that tasks in `on_success` and `on_failure` exist and don't form a cycle.
+fn check_chains(tasks: &[Task]) -> Result<()> {
+    let find = |name: &str| tasks.iter().position(|task| task.name == name);
+    for task in tasks.iter() {
+        for name in task.on_success.iter().chain(task.on_failure.iter()) {
+            if find(name).is_none() {
+                bail!("task `{}` chains to task `{}` which doesn't exist", task.name, name);
+            }
+        }
+    }
+
+    // Depth-first search, a task found again while it's on the current path closes a cycle
+    fn visit(tasks: &[Task], i: usize, path: &mut Vec<usize>, done: &mut Vec<bool>) -> Result<()> {
+        if let Some(start) = path.iter().position(|&j| j == i) {
+            let cycle = path[start..].iter()
+                .chain(Some(&i))
+                .map(|&j| tasks[j].name.as_str())
+                .collect::<Vec<_>>();
+            bail!("tasks chained with `on_success` and `on_failure` form a cycle: {}", cycle.join(" -> "));
+        }
+        if done[i] {
+            return Ok(());
+        }
+        path.push(i);
+        for name in tasks[i].on_success.iter().chain(tasks[i].on_failure.iter()) {
+            let j = tasks.iter().position(|task| &task.name == name).unwrap();
+            visit(tasks, j, path, done)?;
+        }
+        path.pop();
+        done[i] = true;
+        Ok(())
     }
 
+    let mut done = vec![false; tasks.len()];
+    for i in 0..tasks.len() {
+        visit(tasks, i, &mut Vec::new(), &mut done)?;
+    }
     Ok(())
 }
 
@@ -189,6 +576,30 @@ fn parse_task(name: String, table: Table, global: &Config) -> Result<Task> {
     let mut env = global.env.clone();
     let mut clear_env = global.clear_env;
     let mut on_startup = global.on_startup;
+    let mut timeout = None;
+    let mut kill_grace = global.kill_grace;
+    let mut overlap = global.overlap;
+    let mut catch_up = global.catch_up;
+    let mut timezone = None;
+    let mut dst = global.dst;
+    let mut stdout = global.stdout.clone();
+    let mut stderr = global.stderr.clone();
+    let mut retry = None;
+    let mut on_success = Vec::new();
+    let mut on_failure = Vec::new();
+    let mut user = None;
+    let mut group = None;
+    let mut supplementary_groups = None;
+    let mut workdir = global.workdir.clone();
+    let mut umask = global.umask;
+    let mut stdin = global.stdin.clone();
+    let mut nice = None;
+    let mut ioprio = None;
+    let mut rlimits = Vec::new();
+    let mut memory_max = None;
+    let mut cpu_max = None;
+    let mut pids_max = None;
+    let mut kill_leftovers = global.kill_leftovers;
 
     for (key, value) in table.into_iter() {
         match key.as_str() {
@@ -198,13 +609,16 @@ fn parse_task(name: String, table: Table, global: &Config) -> Result<Task> {
                         .context("parsing task command (`cmd`)")?
                 );
             }
-            "after" | "every" | "on" => {
+            "after" | "every" | "on" | "cron" => {
                 if time.is_some() {
-                    bail!("only one timing (options `after`, `every` and `on`) can be set");
+                    bail!("only one timing (options `after`, `every`, `on` and `cron`) can be set");
                 }
                 time = Some(
-                    parse_time(&key, value)
-                        .with_context(|| format!("parsing task timing (`{}`)", &key))?
+                    match key.as_str() {
+                        "cron" => parse_string(value).and_then(|expr| parse_cron(&expr)),
+                        _ => parse_time(&key, value),
+                    }
+                    .with_context(|| format!("parsing task timing (`{}`)", &key))?
                 );
             }
             "shell" => {
@@ -227,21 +641,209 @@ fn parse_task(name: String, table: Table, global: &Config) -> Result<Task> {
                 on_startup = parse_bool(value)
                     .context("parsing task `on_startup`")?;
             }
+            "timeout" => {
+                let duration = parse_table(value)
+                    .and_then(parse_duration)
+                    .context("parsing task `timeout`")?;
+                if duration < Duration::seconds(1) {
+                    bail!("task `timeout` must be at least 1 second, found `{:?}`", duration);
+                }
+                timeout = Some(duration);
+            }
+            "kill_grace" => {
+                kill_grace = parse_table(value)
+                    .and_then(parse_duration)
+                    .context("parsing task `kill_grace`")?;
+            }
+            "overlap" => {
+                overlap = parse_overlap(value)
+                    .context("parsing task `overlap`")?;
+            }
+            "catch_up" => {
+                catch_up = parse_bool(value)
+                    .context("parsing task `catch_up`")?;
+            }
+            "timezone" => {
+                timezone = Some(
+                    parse_timezone(value)
+                        .context("parsing task `timezone`")?
+                );
+            }
+            "dst_gap" => {
+                dst.gap = parse_dst_gap(value)
+                    .context("parsing task `dst_gap`")?;
+            }
+            "dst_repeat" => {
+                dst.repeat = parse_dst_repeat(value)
+                    .context("parsing task `dst_repeat`")?;
+            }
+            "stdout" => {
+                stdout = parse_output(value)
+                    .context("parsing task `stdout`")?;
+            }
+            "stderr" => {
+                stderr = parse_output(value)
+                    .context("parsing task `stderr`")?;
+            }
+            "retry" => {
+                retry = Some(
+                    parse_retry(value)
+                        .context("parsing task `retry`")?
+                );
+            }
+            "on_success" => {
+                on_success = parse_task_names(value)
+                    .context("parsing task `on_success`")?;
+            }
+            "on_failure" => {
+                on_failure = parse_task_names(value)
+                    .context("parsing task `on_failure`")?;
+            }
+            "user" => {
+                user = Some(
+                    parse_user(value)
+                        .context("parsing task `user`")?
+                );
+            }
+            "group" => {
+                group = Some(
+                    parse_group(value)
+                        .context("parsing task `group`")?
+                );
+            }
+            "supplementary_groups" => {
+                supplementary_groups = Some(
+                    parse_groups(value)
+                        .context("parsing task `supplementary_groups`")?
+                );
+            }
+            "workdir" => {
+                workdir = Some(
+                    parse_workdir(value)
+                        .context("parsing task `workdir`")?
+                );
+            }
+            "umask" => {
+                umask = Some(
+                    parse_umask(value)
+                        .context("parsing task `umask`")?
+                );
+            }
+            "stdin" => {
+                stdin = parse_input(value)
+                    .context("parsing task `stdin`")?;
+            }
+            "nice" => {
+                let value = parse_integer(value)
+                    .context("parsing task `nice`")?;
+                if !(-20..=19).contains(&value) {
+                    bail!("task `nice` must be between -20 and 19, found `{}`", value);
+                }
+                nice = Some(value as i32);
+            }
+            "ioprio" => {
+                ioprio = Some(
+                    parse_ioprio(value)
+                        .context("parsing task `ioprio`")?
+                );
+            }
+            "rlimits" => {
+                rlimits = parse_rlimits(value)
+                    .context("parsing task `rlimits`")?;
+            }
+            "memory_max" => {
+                memory_max = Some(
+                    parse_size(value)
+                        .context("parsing task `memory_max`")?
+                );
+            }
+            "cpu_max" => {
+                let cpus = parse_float(value)
+                    .context("parsing task `cpu_max`")?;
+                if cpus.is_nan() || cpus <= 0.0 || cpus > 4096.0 {
+                    bail!("task `cpu_max` must be a number of CPUs > 0, found `{}`", cpus);
+                }
+                cpu_max = Some(cpus);
+            }
+            "pids_max" => {
+                let max = parse_integer(value)
+                    .context("parsing task `pids_max`")?;
+                if max < 1 {
+                    bail!("task `pids_max` must be >= 1, found `{}`", max);
+                }
+                pids_max = Some(max as u64);
+            }
+            "kill_leftovers" => {
+                kill_leftovers = parse_bool(value)
+                    .context("parsing task `kill_leftovers`")?;
+            }
             "name" => {
                 // nop
             }
             _ => {
-                bail!("unknown task option, valid options are `name`, `cmd`, `after`, `every`, `on`, `shell`, \
-                      `clear_env` and `on_startup`");
+                bail!("unknown task option, valid options are `name`, `cmd`, `after`, `every`, `on`, `cron`, \
+                      `shell`, `clear_env`, `on_startup`, `timeout`, `kill_grace`, `overlap`, `catch_up`, \
+                      `timezone`, `dst_gap`, `dst_repeat`, `stdout`, `stderr`, \
+                      `retry`, `on_success`, `on_failure`, `user`, `group`, `supplementary_groups`, \
+                      `workdir`, `umask`, `stdin`, `nice`, `ioprio`, `rlimits`, \
+                      `memory_max`, `cpu_max`, `pids_max` and `kill_leftovers`");
             }
         }
     }
 
     let command = command.ok_or_else(|| anyhow!("missing task command, use option `cmd`"))?;
-    let time = time.ok_or_else(|| anyhow!("missing task timing, use one option of `after`, `every` or `on`"))?;
     let shell = shell.unwrap_or_else(|| global.shell.clone());
+    let timezone = timezone.unwrap_or_else(|| global.timezone.clone());
+    if global.cgroup_root.is_none() && (memory_max.is_some() || cpu_max.is_some() || pids_max.is_some()) {
+        bail!("options `memory_max`, `cpu_max` and `pids_max` require the global `cgroup_root` option");
+    }
+    // Like login, the user gets their primary group and groups from the group database, unless
+    // set explicitly.  Changing just the group drops supplementary groups of ocron.
+    let group = group.or_else(|| user.as_ref().map(|user: &Passwd| user.gid));
+    let supplementary_groups = match (supplementary_groups, &user, group) {
+        (Some(groups), _, _) => Some(groups),
+        (None, Some(user), Some(gid)) => Some(
+            sys::group_list(&user.name, gid)
+                .with_context(|| format!("listing groups of user `{}`", user.name))?
+        ),
+        (None, None, Some(_)) => Some(Vec::new()),
+        (None, _, None) => None,
+    };
 
-    Ok(Task { name, command, time, shell, env, clear_env, on_startup })
+    Ok(Task {
+        name,
+        command,
+        time,
+        shell,
+        env,
+        clear_env,
+        on_startup,
+        timeout,
+        kill_grace,
+        overlap,
+        catch_up,
+        timezone,
+        dst,
+        stdout,
+        stderr,
+        retry,
+        on_success,
+        on_failure,
+        user,
+        group,
+        supplementary_groups,
+        workdir,
+        umask,
+        stdin,
+        nice,
+        ioprio,
+        rlimits,
+        cgroup_root: global.cgroup_root.clone(),
+        memory_max,
+        cpu_max,
+        pids_max,
+        kill_leftovers,
+    })
 }
 
 fn parse_command(value: Value) -> Result<Command> {
@@ -268,6 +870,415 @@ fn parse_command(value: Value) -> Result<Command> {
     }
 }
 
+fn parse_overlap(value: Value) -> Result<Overlap> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "allow" => Ok(Overlap::Allow),
+        "skip" => Ok(Overlap::Skip),
+        "queue" => Ok(Overlap::Queue),
+        "replace" => Ok(Overlap::Replace),
+        _ => bail!("invalid overlap policy `{}`, valid policies are `allow`, `skip`, `queue` and `replace`", string),
+    }
+}
+
+fn parse_timezone(value: Value) -> Result<Zone> {
+    let string = parse_string(value)?;
+    Zone::named(&string)
+}
+
+fn parse_dst_gap(value: Value) -> Result<DstGap> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "skip" => Ok(DstGap::Skip),
+        "next" => Ok(DstGap::Next),
+        _ => bail!("invalid value `{}`, valid values are `skip` and `next`", string),
+    }
+}
+
+fn parse_log_format(value: Value) -> Result<log::Format> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "text" => Ok(log::Format::Text),
+        "json" => Ok(log::Format::Json),
+        _ => bail!("invalid value `{}`, valid values are `text` and `json`", string),
+    }
+}
+
+fn parse_log_level(value: Value) -> Result<log::Level> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "error" => Ok(log::Level::Error),
+        "warn" => Ok(log::Level::Warn),
+        "info" => Ok(log::Level::Info),
+        "debug" => Ok(log::Level::Debug),
+        _ => bail!("invalid value `{}`, valid values are `error`, `warn`, `info` and `debug`", string),
+    }
+}
+
+fn parse_log_target(value: Value) -> Result<log::Target> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "stderr" => Ok(log::Target::Stderr),
+        "syslog" => Ok(log::Target::Syslog),
+        "journald" => Ok(log::Target::Journald),
+        _ => bail!("invalid value `{}`, valid values are `stderr`, `syslog` and `journald`", string),
+    }
+}
+
+fn parse_dst_repeat(value: Value) -> Result<DstRepeat> {
+    let string = parse_string(value)?;
+    match string.as_str() {
+        "first" => Ok(DstRepeat::First),
+        "both" => Ok(DstRepeat::Both),
+        _ => bail!("invalid value `{}`, valid values are `first` and `both`", string),
+    }
+}
+
+fn parse_workdir(value: Value) -> Result<PathBuf> {
+    let path = PathBuf::from(parse_string(value)?);
+    if !path.is_dir() {
+        bail!("working directory {:?} doesn't exist or isn't a directory", path);
+    }
+    Ok(path)
+}
+
+// Parses a signal given by name, with or without the `SIG` prefix, or by number.
+fn parse_signal(value: Value) -> Result<libc::c_int> {
+    match value {
+        Value::String(string) => {
+            let name = string.strip_prefix("SIG").unwrap_or(&string);
+            sys::SIGNALS.iter()
+                .find(|(signal_name, _)| signal_name.strip_prefix("SIG") == Some(name))
+                .map(|&(_, signal)| signal)
+                .ok_or_else(|| anyhow!("unknown signal `{}`", string))
+        }
+        Value::Integer(int) => {
+            if !(1..=64).contains(&int) {
+                bail!("invalid signal number `{}`", int);
+            }
+            Ok(int as libc::c_int)
+        }
+        _ => bail!("expected signal name or number, found `{:?}`", value),
+    }
+}
+
+// Parses a cgroup v2 directory, which has to exist already.
+fn parse_cgroup_root(value: Value) -> Result<PathBuf> {
+    let path = PathBuf::from(parse_string(value)?);
+    if !path.join("cgroup.procs").is_file() {
+        bail!("{:?} isn't a directory in a mounted cgroup v2 hierarchy", path);
+    }
+    Ok(path)
+}
+
+// Parses a umask given as an integer, preferably octal like `0o022`, or an octal string.
+fn parse_umask(value: Value) -> Result<u32> {
+    let umask = match value {
+        Value::Integer(int) => int,
+        Value::String(string) => i64::from_str_radix(&string, 8)
+            .with_context(|| format!("invalid octal umask `{}`", string))?,
+        _ => bail!("expected integer or octal string, found `{:?}`", value),
+    };
+    if !(0..=0o777).contains(&umask) {
+        bail!("umask must be between 0o000 and 0o777, found `{:#o}`", umask);
+    }
+    Ok(umask as u32)
+}
+
+fn parse_input(value: Value) -> Result<Input> {
+    match value {
+        Value::String(string) => match string.as_str() {
+            "inherit" => Ok(Input::Inherit),
+            "null" => Ok(Input::Null),
+            _ => Ok(Input::File(PathBuf::from(string))),
+        },
+        Value::Table(table) => {
+            let mut entries = table.into_iter();
+            match (entries.next(), entries.next()) {
+                (Some((key, value)), None) if key == "file" => {
+                    Ok(Input::File(PathBuf::from(parse_string(value).context("parsing option `file`")?)))
+                }
+                (Some((key, value)), None) if key == "text" => {
+                    Ok(Input::Text(parse_string(value).context("parsing option `text`")?))
+                }
+                _ => bail!("expected a table with exactly one of options `file` and `text`"),
+            }
+        }
+        _ => bail!("expected `inherit`, `null`, a file path or a table, found `{:?}`", value),
+    }
+}
+
+fn parse_ioprio(value: Value) -> Result<Ioprio> {
+    let table = parse_table(value)?;
+    let mut class = None;
+    let mut level = None;
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "class" => {
+                let string = parse_string(value)
+                    .context("parsing option `class`")?;
+                class = Some(match string.as_str() {
+                    "realtime" => IoprioClass::Realtime,
+                    "best-effort" => IoprioClass::BestEffort,
+                    "idle" => IoprioClass::Idle,
+                    _ => bail!("invalid I/O scheduling class `{}`, valid classes are `realtime`, `best-effort` \
+                                and `idle`", string),
+                });
+            }
+            "level" => {
+                level = Some(
+                    parse_ranged_integer(value, 0..8)
+                        .context("parsing option `level`")?
+                );
+            }
+            _ => bail!("unknown ioprio option `{}`, valid options are `class` and `level`", key),
+        }
+    }
+
+    let class = class.ok_or_else(|| anyhow!("missing I/O scheduling `class`"))?;
+    if class == IoprioClass::Idle && level.is_some() {
+        bail!("the `idle` class has no levels");
+    }
+    Ok(Ioprio { class, level: level.unwrap_or(4) })
+}
+
+fn parse_rlimits(value: Value) -> Result<Vec<Rlimit>> {
+    let table = parse_table(value)?;
+    table.into_iter()
+        .map(|(key, value)| {
+            let resource = match key.as_str() {
+                "nofile" => Resource::Nofile,
+                "as" => Resource::As,
+                "cpu" => Resource::Cpu,
+                "core" => Resource::Core,
+                "nproc" => Resource::Nproc,
+                "fsize" => Resource::Fsize,
+                _ => bail!("unknown resource `{}`, valid resources are `nofile`, `as`, `cpu`, `core`, `nproc` \
+                            and `fsize`", key),
+            };
+            let (soft, hard) = parse_limits(value)
+                .with_context(|| format!("parsing limit `{}`", key))?;
+            if soft.unwrap_or(u64::MAX) > hard.unwrap_or(u64::MAX) {
+                bail!("soft limit of `{}` is higher than the hard limit", key);
+            }
+            Ok(Rlimit { resource, soft, hard })
+        })
+        .collect()
+}
+
+// Parses soft and hard limits, either a table with both or one value used for both.
+fn parse_limits(value: Value) -> Result<(Option<u64>, Option<u64>)> {
+    match value {
+        Value::Table(table) => {
+            let mut soft = None;
+            let mut hard = None;
+            for (key, value) in table.into_iter() {
+                match key.as_str() {
+                    "soft" => soft = Some(parse_limit(value).context("parsing option `soft`")?),
+                    "hard" => hard = Some(parse_limit(value).context("parsing option `hard`")?),
+                    _ => bail!("unknown limit option `{}`, valid options are `soft` and `hard`", key),
+                }
+            }
+            match (soft, hard) {
+                (Some(soft), Some(hard)) => Ok((soft, hard)),
+                _ => bail!("both `soft` and `hard` limits have to be set"),
+            }
+        }
+        value => {
+            let limit = parse_limit(value)?;
+            Ok((limit, limit))
+        }
+    }
+}
+
+// Parses a resource limit, a size like `parse_size` or `unlimited`.
+fn parse_limit(value: Value) -> Result<Option<u64>> {
+    match value {
+        Value::String(string) if string == "unlimited" => Ok(None),
+        value => parse_size(value).map(Some),
+    }
+}
+
+fn parse_user(value: Value) -> Result<Passwd> {
+    let user = match value {
+        Value::String(name) => sys::user_by_name(&name)
+            .with_context(|| format!("looking up user `{}`", name))?
+            .ok_or_else(|| anyhow!("user `{}` doesn't exist", name))?,
+        Value::Integer(uid) => {
+            let uid = u32::try_from(uid)
+                .map_err(|_| anyhow!("invalid uid `{}`", uid))?;
+            sys::user_by_uid(uid)
+                .with_context(|| format!("looking up uid {}", uid))?
+                .ok_or_else(|| anyhow!("user with uid {} doesn't exist", uid))?
+        }
+        _ => bail!("expected user name or uid, found `{:?}`", value),
+    };
+    Ok(user)
+}
+
+fn parse_group(value: Value) -> Result<u32> {
+    match value {
+        Value::String(name) => sys::group_by_name(&name)
+            .with_context(|| format!("looking up group `{}`", name))?
+            .ok_or_else(|| anyhow!("group `{}` doesn't exist", name)),
+        Value::Integer(gid) => u32::try_from(gid)
+            .map_err(|_| anyhow!("invalid gid `{}`", gid)),
+        _ => bail!("expected group name or gid, found `{:?}`", value),
+    }
+}
+
+fn parse_groups(value: Value) -> Result<Vec<u32>> {
+    match value {
+        Value::Array(groups) => groups.into_iter()
+            .map(parse_group)
+            .collect(),
+        _ => bail!("expected array of groups, found `{:?}`", value),
+    }
+}
+
+fn parse_task_names(value: Value) -> Result<Vec<String>> {
+    match value {
+        Value::Array(names) => names.into_iter()
+            .map(parse_string)
+            .collect(),
+        _ => bail!("expected array of task names, found `{:?}`", value),
+    }
+}
+
+fn parse_retry(value: Value) -> Result<Retry> {
+    let table = parse_table(value)?;
+    let mut attempts = None;
+    let mut delay = Duration::minutes(1);
+    let mut backoff = 1.0;
+    let mut max_delay = None;
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "attempts" => {
+                let count = parse_integer(value)
+                    .context("parsing option `attempts`")?;
+                if count < 1 { bail!("number of `attempts` must be >= 1"); }
+                attempts = Some(
+                    u32::try_from(count)
+                        .map_err(|_| anyhow!("number of `attempts` is too large"))?
+                );
+            }
+            "delay" => {
+                delay = parse_table(value)
+                    .and_then(parse_duration)
+                    .context("parsing option `delay`")?;
+            }
+            "backoff" => {
+                backoff = parse_float(value)
+                    .context("parsing option `backoff`")?;
+                if backoff.is_nan() || backoff < 1.0 { bail!("`backoff` must be >= 1.0"); }
+            }
+            "max_delay" => {
+                max_delay = Some(
+                    parse_table(value)
+                        .and_then(parse_duration)
+                        .context("parsing option `max_delay`")?
+                );
+            }
+            _ => bail!("unknown retry option `{}`, valid options are `attempts`, `delay`, `backoff` and \
+                        `max_delay`", key),
+        }
+    }
+
+    let attempts = attempts.ok_or_else(|| anyhow!("missing number of `attempts`"))?;
+    Ok(Retry { attempts, delay, backoff, max_delay })
+}
+
+fn parse_output(value: Value) -> Result<Output> {
+    let table = match value {
+        Value::String(string) => match string.as_str() {
+            "inherit" => return Ok(Output::Inherit),
+            "null" => return Ok(Output::Null),
+            "log" => return Ok(Output::Log),
+            _ => {
+                let mut table = Table::new();
+                table.insert(String::from("file"), Value::String(string));
+                table
+            }
+        },
+        Value::Table(table) => table,
+        _ => bail!("expected `inherit`, `null`, `log`, a file path or a table, found `{:?}`", value),
+    };
+
+    let mut path = None;
+    let mut max_size = None;
+    let mut keep = None;
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "file" => {
+                let string = parse_string(value)
+                    .context("parsing option `file`")?;
+                check_path_template(&string)?;
+                path = Some(string);
+            }
+            "max_size" => {
+                let size = parse_size(value)
+                    .context("parsing option `max_size`")?;
+                if size == 0 { bail!("`max_size` must be > 0"); }
+                max_size = Some(size);
+            }
+            "keep" => {
+                let count = parse_integer(value)
+                    .context("parsing option `keep`")?;
+                keep = Some(
+                    u32::try_from(count)
+                        .map_err(|_| anyhow!("number of files to `keep` must be >= 0"))?
+                );
+            }
+            _ => bail!("unknown output option `{}`, valid options are `file`, `max_size` and `keep`", key),
+        }
+    }
+
+    let path = path.ok_or_else(|| anyhow!("missing output file path, use option `file`"))?;
+    if keep.is_some() && max_size.is_none() {
+        bail!("option `keep` requires `max_size`");
+    }
+    Ok(Output::File(OutputFile { path, max_size, keep: keep.unwrap_or(5) }))
+}
+
+// Checks that a path template only uses known placeholders.
+fn check_path_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}')
+            .ok_or_else(|| anyhow!("unclosed placeholder in `{}`", template))?;
+        match &rest[start + 1..start + end] {
+            "name" | "date" => {}
+            other => bail!("unknown placeholder `{{{}}}`, valid placeholders are `{{name}}` and `{{date}}`", other),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+// Parses a size in bytes, either an integer or a string with a `K`, `M` or `G` suffix.
+fn parse_size(value: Value) -> Result<u64> {
+    let (number, multiplier) = match value {
+        Value::Integer(int) => (int, 1),
+        Value::String(string) => {
+            let (number, multiplier) = match string.char_indices().last() {
+                Some((i, 'K')) => (&string[..i], 1 << 10),
+                Some((i, 'M')) => (&string[..i], 1 << 20),
+                Some((i, 'G')) => (&string[..i], 1 << 30),
+                _ => (string.as_str(), 1),
+            };
+            let number = number.parse::<i64>()
+                .with_context(|| format!("invalid size `{}`, expected a number with an optional `K`, `M` or `G` suffix", string))?;
+            (number, multiplier)
+        }
+        _ => bail!("expected integer or string, found `{:?}`", value),
+    };
+    if number < 0 {
+        bail!("size must be >= 0");
+    }
+    (number as u64).checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("size is too large"))
+}
+
 fn parse_weekday(value: Value) -> Result<Weekday> {
     let string = parse_string(value)?;
     string.parse()
@@ -285,70 +1296,240 @@ fn parse_ranged_integer(value: Value, range: Range<i64>) -> Result<u32> {
     Ok(int as u32)
 }
 
-fn parse_one_or_array_ranged(value: Value, range: Range<i64>) -> Result<Vec<u32>> {
+fn parse_range_table(table: Table, range: Range<i64>) -> Result<Vec<u32>> {
+    let mut from = range.start as u32;
+    let mut to = (range.end - 1) as u32;
+    let mut step = 1;
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "from" => {
+                from = parse_ranged_integer(value, range.clone())
+                    .context("parsing option `from`")?;
+            }
+            "to" => {
+                to = parse_ranged_integer(value, range.clone())
+                    .context("parsing option `to`")?;
+            }
+            "step" => {
+                step = parse_integer(value)
+                    .context("parsing option `step`")?;
+                if step < 1 { bail!("`step` must be >= 1"); }
+            }
+            _ => bail!("unknown range option `{}`, valid options are `from`, `to` and `step`", key),
+        }
+    }
+
+    if from > to {
+        bail!("range from {} to {} is empty", from, to);
+    }
+    Ok((from..=to).step_by(step as usize).collect())
+}
+
+// Parses a single value, a range expression like `"9-17"` or `"*/5"`, or a range table like
+// `{ from = 9, to = 17, step = 2 }`.
+fn parse_ranged_values(value: Value, range: Range<i64>) -> Result<Vec<u32>> {
     match value {
         Value::Integer(_) => Ok(vec![parse_ranged_integer(value, range)?]),
+        Value::String(expr) => parse_range_expr(&expr, range, &[]),
+        Value::Table(table) => parse_range_table(table, range),
+        _ => bail!("expected integer, string or table, found `{:?}`", value),
+    }
+}
+
+fn parse_one_or_array_ranged(value: Value, range: Range<i64>) -> Result<Vec<u32>> {
+    match value {
         Value::Array(array) => {
             let vec = array.into_iter()
-                .map(|value| parse_ranged_integer(value, range.clone()))
-                .collect::<Result<Vec<_>>>()?;
+                .map(|value| parse_ranged_values(value, range.clone()))
+                .collect::<Result<Vec<_>>>()?
+                .concat();
             if vec.is_empty() {
                 bail!("array must contain at least one value, to use the default values skip the \
                        option completely");
             }
             Ok(vec)
         }
-        _ => bail!("expected integer or array, found `{:?}`", value),
+        _ => parse_ranged_values(value, range),
+    }
+}
+
+const MONTH_NAMES: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const WEEKDAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Parses a comma separated list of values and ranges as used in cron expressions, for example
+// `1,5-10,20-40/5,*/15`.  Values can also be given using `names`, where the first name stands for
+// `range.start`.  Returns the matched values sorted and deduplicated.
+fn parse_range_expr(expr: &str, range: Range<i64>, names: &[&str]) -> Result<Vec<u32>> {
+    assert!(u32::try_from(range.start).is_ok());
+    assert!(u32::try_from(range.end - 1).is_ok());
+
+    let parse_value = |string: &str| -> Result<i64> {
+        if let Some(index) = names.iter().position(|name| name.eq_ignore_ascii_case(string)) {
+            return Ok(range.start + index as i64);
+        }
+        let int = string.parse::<i64>()
+            .map_err(|_| anyhow!("invalid value `{}`", string))?;
+        if !range.contains(&int) {
+            bail!("value `{}` is out of range {}..{}", int, range.start, range.end);
+        }
+        Ok(int)
+    };
+
+    let mut values = Vec::new();
+    for item in expr.split(',') {
+        let (span, step) = match item.split_once('/') {
+            Some((span, step)) => {
+                let step = step.parse::<usize>()
+                    .map_err(|_| anyhow!("invalid step `{}`", step))?;
+                if step == 0 {
+                    bail!("step must be at least 1");
+                }
+                (span, Some(step))
+            }
+            None => (item, None),
+        };
+
+        let (start, end) = if span == "*" {
+            (range.start, range.end - 1)
+        } else if let Some((start, end)) = span.split_once('-') {
+            (parse_value(start)?, parse_value(end)?)
+        } else if step.is_some() {
+            // `start/step` goes until the end of the range
+            (parse_value(span)?, range.end - 1)
+        } else {
+            let value = parse_value(span)?;
+            (value, value)
+        };
+
+        if start > end {
+            bail!("range `{}` is empty", span);
+        }
+        values.extend(
+            (start..=end)
+                .step_by(step.unwrap_or(1))
+                .map(|value| value as u32)
+        );
     }
+
+    values.sort_unstable();
+    values.dedup();
+    Ok(values)
+}
+
+// Parses a classic cron expression into `Time::On`.
+//
+// Supports the usual five fields (`minute hour day month weekday`), an optional leading `second`
+// field and the `@hourly`, `@daily` etc. shorthands.  Unlike cron which runs the task when either
+// the day of the month or the day of the week matches, `Time::On` requires both to match, so
+// expressions restricting both are rejected.
+fn parse_cron(expr: &str) -> Result<Time> {
+    let expr = match expr.trim() {
+        "@yearly" | "@annually" => "0 0 1 1 *",
+        "@monthly" => "0 0 1 * *",
+        "@weekly" => "0 0 * * sun",
+        "@daily" | "@midnight" => "0 0 * * *",
+        "@hourly" => "0 * * * *",
+        expr => expr,
+    };
+
+    let fields = expr.split_whitespace().collect::<Vec<_>>();
+    let (second, fields) = match fields.len() {
+        5 => (vec![0], &fields[..]),
+        6 => {
+            let second = parse_range_expr(fields[0], 0..60, &[])
+                .context("parsing cron field `second`")?;
+            (second, &fields[1..])
+        }
+        n => bail!("expected 5 or 6 fields in cron expression, found {}", n),
+    };
+
+    let minute = parse_range_expr(fields[0], 0..60, &[])
+        .context("parsing cron field `minute`")?;
+    let hour = parse_range_expr(fields[1], 0..24, &[])
+        .context("parsing cron field `hour`")?;
+    let mut day = parse_range_expr(fields[2], 1..32, &[])
+        .context("parsing cron field `day`")?;
+    let mut month = parse_range_expr(fields[3], 1..13, MONTH_NAMES)
+        .context("parsing cron field `month`")?;
+    // Both 0 and 7 are Sunday
+    let mut weekday = parse_range_expr(fields[4], 0..8, WEEKDAY_NAMES)
+        .context("parsing cron field `weekday`")?
+        .into_iter()
+        .map(|n| (0..n).fold(Weekday::Sun, |weekday, _| weekday.succ()))
+        .collect::<Vec<_>>();
+    weekday.sort_unstable_by_key(Weekday::number_from_monday); weekday.dedup();
+
+    // Criteria matching everything are left empty
+    if day.len() == 31 { day.clear(); }
+    if month.len() == 12 { month.clear(); }
+    if weekday.len() == 7 { weekday.clear(); }
+
+    if !day.is_empty() && !weekday.is_empty() {
+        bail!("cron expressions restricting both the day of the month and the day of the week are not \
+               supported, cron runs when either of them matches but ocron requires both to match, use \
+               `on` instead");
+    }
+
+    Ok(Time::On { second, minute, hour, day, month, weekday })
+}
+
+// Parses a duration given as a table of units, like `{ minutes = 5, seconds = 30 }`.
+fn parse_duration(table: Table) -> Result<Duration> {
+    let mut seconds = 0;
+    let mut minutes = 0;
+    let mut hours = 0;
+    let mut days = 0;
+    let mut weeks = 0;
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "seconds" => {
+                seconds = parse_integer(value)
+                    .context("parsing option `seconds`")?;
+                if seconds < 0 { bail!("number of `seconds` must be >= 0"); }
+            }
+            "minutes" => {
+                minutes = parse_integer(value)
+                    .context("parsing option `minutes`")?;
+                if minutes < 0 { bail!("number of `minutes` must be >= 0"); }
+            },
+            "hours" => {
+                hours = parse_integer(value)
+                    .context("parsing option `hours`")?;
+                if hours < 0 { bail!("number of `hours` must be >= 0"); }
+            },
+            "days" => {
+                days = parse_integer(value)
+                    .context("parsing option `days`")?;
+                if days < 0 { bail!("number of `days` must be >= 0"); }
+            },
+            "weeks" => {
+                weeks = parse_integer(value)
+                    .context("parsing option `weeks`")?;
+                if weeks < 0 { bail!("number of `weeks` must be >= 0"); }
+            },
+            _ => bail!("unknown time option (unit) `{}`, valid units are `seconds`, `minutes`, `hours`, \
+                        `days` and `weeks`", key),
+        };
+    }
+
+    let duration =
+        Duration::seconds(seconds) +
+        Duration::minutes(minutes) +
+        Duration::hours(hours) +
+        Duration::days(days) +
+        Duration::weeks(weeks);
+
+    Ok(duration)
 }
 
 fn parse_time(variant: &str, value: Value) -> Result<Time> {
     let table = parse_table(value)?;
     match variant {
         "after" | "every" => {
-            let mut seconds = 0;
-            let mut minutes = 0;
-            let mut hours = 0;
-            let mut days = 0;
-            let mut weeks = 0;
-            for (key, value) in table.into_iter() {
-                match key.as_str() {
-                    "seconds" => {
-                        seconds = parse_integer(value)
-                            .context("parsing option `seconds`")?;
-                        if seconds < 0 { bail!("number of `seconds` must be >= 0"); }
-                    }
-                    "minutes" => {
-                        minutes = parse_integer(value)
-                            .context("parsing option `minutes`")?;
-                        if minutes < 0 { bail!("number of `minutes` must be >= 0"); }
-                    },
-                    "hours" => {
-                        hours = parse_integer(value)
-                            .context("parsing option `hours`")?;
-                        if hours < 0 { bail!("number of `hours` must be >= 0"); }
-                    },
-                    "days" => {
-                        days = parse_integer(value)
-                            .context("parsing option `days`")?;
-                        if days < 0 { bail!("number of `days` must be >= 0"); }
-                    },
-                    "weeks" => {
-                        weeks = parse_integer(value)
-                            .context("parsing option `weeks`")?;
-                        if weeks < 0 { bail!("number of `weeks` must be >= 0"); }
-                    },
-                    _ => bail!("unknown time option (unit) `{}`, valid units are `seconds`, `minutes`, `hours`, \
-                                `days` and `weeks`", key),
-                };
-            }
-
-            let duration =
-                Duration::seconds(seconds) +
-                Duration::minutes(minutes) +
-                Duration::hours(hours) +
-                Duration::days(days) +
-                Duration::weeks(weeks);
+            let duration = parse_duration(table)?;
 
             if duration < Duration::seconds(1) {
                 bail!("time interval must be at least 1 second, found `{:?}`", duration);
@@ -444,3 +1625,157 @@ fn parse_time(variant: &str, value: Value) -> Result<Time> {
         _ => unreachable!()
     }
 }
+
+#[cfg(test)]
+#[test]
+fn cron_expressions() {
+    let parse = |expr| match parse_cron(expr).unwrap() {
+        Time::On { second, minute, hour, weekday, day, month } => (second, minute, hour, weekday, day, month),
+        _ => unreachable!(),
+    };
+
+    let (second, minute, hour, weekday, day, month) = parse("*/15 9-17 * * mon-fri");
+    assert_eq!(second, vec![0]);
+    assert_eq!(minute, vec![0, 15, 30, 45]);
+    assert_eq!(hour, (9..=17).collect::<Vec<_>>());
+    assert_eq!(weekday, vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri]);
+    assert!(day.is_empty() && month.is_empty());
+
+    let (second, minute, hour, weekday, day, month) = parse("30 0 0,12 1/10 JAN,jul 0-7");
+    assert_eq!(second, vec![30]);
+    assert_eq!(minute, vec![0]);
+    assert_eq!(hour, vec![0, 12]);
+    assert_eq!(day, vec![1, 11, 21, 31]);
+    assert_eq!(month, vec![1, 7]);
+    assert!(weekday.is_empty());
+
+    assert_eq!(parse("@daily"), parse("0 0 * * *"));
+    assert_eq!(parse("0 0 * * 7").3, vec![Weekday::Sun]);
+
+    assert!(parse_cron("* * * *").is_err());
+    assert!(parse_cron("60 * * * *").is_err());
+    assert!(parse_cron("*/0 * * * *").is_err());
+    assert!(parse_cron("0 0 17-9 * *").is_err());
+    assert!(parse_cron("0 0 1 * mon").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn on_ranges() {
+    let parse = |toml: &str| {
+        let mut table = toml::from_str::<Table>(&format!("v = {}", toml)).unwrap();
+        parse_one_or_array_ranged(table.remove("v").unwrap(), 0..24).ok()
+    };
+
+    assert_eq!(parse("5"), Some(vec![5]));
+    assert_eq!(parse("\"9-12\""), Some(vec![9, 10, 11, 12]));
+    assert_eq!(parse("\"*/6\""), Some(vec![0, 6, 12, 18]));
+    assert_eq!(parse("[1, \"0-20/10\"]"), Some(vec![1, 0, 10, 20]));
+    assert_eq!(parse("{ from = 9, to = 17, step = 4 }"), Some(vec![9, 13, 17]));
+    assert_eq!(parse("[0, { from = 20 }]"), Some(vec![0, 20, 21, 22, 23]));
+    assert_eq!(parse("{ from = 17, to = 9 }"), None);
+    assert_eq!(parse("{ step = 0 }"), None);
+    assert_eq!(parse("\"23-24\""), None);
+    assert_eq!(parse("[]"), None);
+}
+
+#[cfg(test)]
+#[test]
+fn config_problems() {
+    let parse = |toml: &str| {
+        let (config, problems) = parse_config(toml::from_str::<Table>(toml).unwrap());
+        let problems = problems.iter().map(|e| format!("{:#}", e)).collect::<Vec<_>>();
+        (config.tasks.iter().map(|task| task.name.clone()).collect::<Vec<_>>(), problems)
+    };
+
+    // Every problem is reported and the valid parts are kept
+    let (tasks, problems) = parse(r#"
+        shell = 1
+        nonsense = true
+        catch_up = true
+        [[task]]
+        name = "a"
+        cmd = "true"
+        every = { seconds = 1 }
+        [[task]]
+        name = "a"
+        cmd = "true"
+        every = { seconds = 1 }
+        [[task]]
+        name = "b"
+        every = { seconds = 1 }
+    "#);
+    assert_eq!(tasks, vec!["a"]);
+    assert_eq!(problems.len(), 5, "{:?}", problems);
+    assert!(problems[0].starts_with("unknown option `nonsense`"));
+    assert!(problems[1].starts_with("parsing global `shell`"));
+    assert!(problems[2].contains("duplicate task name `a`"));
+    assert!(problems[3].contains("parsing task `b`"));
+    assert!(problems[4].contains("task `a` has `catch_up` set"));
+
+    // Chains are only checked when every task is valid
+    let (_, problems) = parse(r#"
+        [[task]]
+        name = "a"
+        cmd = "true"
+        every = { seconds = 1 }
+        on_success = ["b"]
+        [[task]]
+        name = "b"
+        every = { seconds = 1 }
+    "#);
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    let (_, problems) = parse(r#"
+        [[task]]
+        name = "a"
+        cmd = "true"
+        every = { seconds = 1 }
+        on_success = ["c"]
+    "#);
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert!(problems[0].contains("chains to task `c`"));
+}
+
+#[cfg(test)]
+#[test]
+fn task_chains() {
+    let chains = |links: &[(&str, &str, &str)]| {
+        let toml = links.iter()
+            .map(|(name, on_success, on_failure)| format!(
+                "[[task]]\nname = \"{}\"\ncmd = \"true\"\non_success = [{}]\non_failure = [{}]\n",
+                name, on_success, on_failure,
+            ))
+            .collect::<String>();
+        Config::parse(&toml).map_err(|e| format!("{:#}", e))
+    };
+
+    // Joining chains are fine as long as they don't loop
+    assert!(chains(&[("a", "\"b\", \"c\"", ""), ("b", "\"d\"", ""), ("c", "", "\"d\""), ("d", "", "")]).is_ok());
+
+    let error = chains(&[("a", "\"b\"", ""), ("b", "", "\"c\""), ("c", "\"b\"", "")]).unwrap_err();
+    assert!(error.ends_with("form a cycle: b -> c -> b"), "{}", error);
+    let error = chains(&[("a", "", "\"a\"")]).unwrap_err();
+    assert!(error.ends_with("form a cycle: a -> a"), "{}", error);
+    let error = chains(&[("a", "\"x\"", "")]).unwrap_err();
+    assert!(error.ends_with("task `a` chains to task `x` which doesn't exist"), "{}", error);
+}
+
+#[cfg(test)]
+#[test]
+fn log_options() {
+    let config = Config::parse("").unwrap();
+    assert_eq!((config.log_format, config.log_level), (log::Format::Text, log::Level::Info));
+    let config = Config::parse("log_format = \"json\"\nlog_level = \"debug\"").unwrap();
+    assert_eq!((config.log_format, config.log_level), (log::Format::Json, log::Level::Debug));
+    let config = Config::parse("log_level = \"warn\"").unwrap();
+    assert_eq!(config.log_level, log::Level::Warn);
+    assert!(log::Level::Error < log::Level::Warn && log::Level::Info < log::Level::Debug);
+
+    let config = Config::parse("log_target = \"journald\"").unwrap();
+    assert_eq!(config.log_target, log::Target::Journald);
+
+    assert!(Config::parse("log_format = \"xml\"").is_err());
+    assert!(Config::parse("log_target = \"file\"").is_err());
+    assert!(Config::parse("log_level = \"WARN\"").is_err());
+    assert!(Config::parse("log_level = 3").is_err());
+}
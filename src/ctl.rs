@@ -0,0 +1,199 @@
+use crate::config::Config;
+use crate::log::Event;
+use crate::queue::Queue;
+use crate::reload;
+use crate::zone::Zone;
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{process, thread};
+
+// The control socket protocol is line based.  The client sends a single line with a command and
+// its argument, `<command> [task_name]`.  The reply starts with a line `ok` or `error: <message>`
+// and in case of success it's followed by the command's output.  Then the connection is closed.
+
+struct Server {
+    config_path: String,
+    // Options which take effect only at startup, see `reload`
+    startup: Vec<(&'static str, String)>,
+    queue: Arc<Queue>,
+    started: DateTime<Utc>,
+    zone: Zone,
+}
+
+// Starts a thread serving the control socket at `socket_path`, each connection is handled in
+// a thread of its own.  It changes the umask for a moment, so it has to be called before any task
+// is started.
+pub fn serve(
+    socket_path: &Path,
+    config_path: &str,
+    queue: Arc<Queue>,
+    startup: Vec<(&'static str, String)>,
+) -> Result<()> {
+    // Don't take the socket away from another running instance
+    if UnixStream::connect(socket_path).is_ok() {
+        bail!("control socket {:?} is already used by another process", socket_path);
+    }
+    match fs::remove_file(socket_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing stale control socket {:?}", socket_path));
+        }
+        _ => {}
+    }
+
+    // Anyone who can connect can run tasks, so the socket is created accessible only to the owner
+    // rather than restricted after it's already there
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    unsafe { libc::umask(umask) };
+    let listener = listener
+        .with_context(|| format!("binding control socket {:?}", socket_path))?;
+
+    let server = Arc::new(Server {
+        config_path: config_path.to_owned(),
+        startup,
+        queue,
+        started: Utc::now(),
+        zone: Zone::local(),
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = server.handle(stream) {
+                            Event::error(format!("control socket: {:#}", e)).emit();
+                        }
+                    });
+                }
+                Err(e) => Event::error(format!("control socket: {}", e)).emit(),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl Server {
+    fn handle(&self, mut stream: UnixStream) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        let reply = match self.execute(line.trim()) {
+            Ok(output) => {
+                let mut reply = String::from("ok\n");
+                output.iter().for_each(|line| {
+                    reply.push_str(line);
+                    reply.push('\n');
+                });
+                reply
+            }
+            Err(e) => format!("error: {:#}\n", e),
+        };
+        stream.write_all(reply.as_bytes())?;
+        Ok(())
+    }
+
+    fn execute(&self, line: &str) -> Result<Vec<String>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let arg = words.next();
+        if words.next().is_some() {
+            bail!("too many arguments");
+        }
+        let task_name = || arg.context("missing argument <task_name>");
+
+        match command {
+            "list" => {
+                let output = self.queue.status().into_iter()
+                    .map(|status| {
+                        format!(
+                            "[{}] next run {}, {} running{}",
+                            status.task.name,
+                            status.next_run.map_or_else(|| "none".into(), |time| status.task.timezone.format(time)),
+                            status.runs.len(),
+                            if status.paused { ", paused" } else { "" },
+                        )
+                    })
+                    .collect();
+                Ok(output)
+            }
+            "trigger" => {
+                self.queue.trigger(task_name()?)?;
+                Ok(vec![])
+            }
+            "pause" => {
+                self.queue.set_paused(task_name()?, true)?;
+                Ok(vec![])
+            }
+            "resume" => {
+                self.queue.set_paused(task_name()?, false)?;
+                Ok(vec![])
+            }
+            "reload" => {
+                reload(&self.config_path, &self.queue, &self.startup)?;
+                Ok(vec![])
+            }
+            "status" => {
+                let tasks = self.queue.status();
+                let mut output = vec![
+                    format!("pid {}", process::id()),
+                    format!("config {:?}", self.config_path),
+                    format!("started {}", self.zone.format(self.started)),
+                    format!(
+                        "{} tasks, {} paused",
+                        tasks.len(),
+                        tasks.iter().filter(|status| status.paused).count(),
+                    ),
+                ];
+                for status in tasks.iter() {
+                    for (pid, start) in status.runs.iter() {
+                        let pid = pid.map_or_else(|| "not spawned yet".into(), |pid| format!("pid {}", pid));
+                        output.push(format!("[{}] running since {}, {}", status.task.name, self.zone.format(*start), pid));
+                    }
+                }
+                Ok(output)
+            }
+            _ => bail!("unknown command `{}`, valid commands are `list`, `trigger`, `pause`, `resume`, `reload` \
+                        and `status`", command),
+        }
+    }
+}
+
+// Sends a command to the control socket of a running ocron and prints the reply.
+pub fn ctl(args: &[String]) -> Result<()> {
+    let path = args.first()
+        .context("missing argument <config_file>")?;
+    let command = args[1..].join(" ");
+    if command.is_empty() {
+        bail!("missing argument <command>");
+    }
+
+    let config = Config::read_file(path)?;
+    let socket_path = config.control_socket
+        .context("the config file doesn't set `control_socket`")?;
+
+    let mut stream = UnixStream::connect(&socket_path)
+        .with_context(|| format!("connecting to control socket {:?}", socket_path))?;
+    writeln!(stream, "{}", command)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)
+        .context("reading reply")?;
+
+    let mut lines = reply.lines();
+    match lines.next() {
+        Some("ok") => {
+            lines.for_each(|line| println!("{}", line));
+            Ok(())
+        }
+        Some(line) if line.starts_with("error: ") => bail!("{}", &line["error: ".len()..]),
+        _ => bail!("invalid reply from control socket: {:?}", reply),
+    }
+}
@@ -0,0 +1,423 @@
+// Logging of OCRON's own events, as plain text lines or as JSON records, to stderr, syslog or the
+// systemd journal.
+//
+// Every record has a level, a kind of event, an optional task name, a human readable message and
+// optional fields, which show up in JSON, as structured data in syslog and as journal fields.
+
+use crate::sys;
+use chrono::prelude::*;
+use serde_json::{Map, Value};
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Records are sent outside of the lock, so that a full socket buffer can't block every thread
+// which logs, some of them while holding the queue.
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    format: Format::Text,
+    level: Level::Info,
+    target: Target::Stderr,
+    socket: None,
+});
+
+struct Logger {
+    format: Format,
+    level: Level,
+    target: Target,
+    // Connected non-blocking socket of the syslog or journal target
+    socket: Option<Arc<UnixDatagram>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+pub fn init(format: Format, level: Level, target: Target) -> io::Result<()> {
+    let socket = target.path().map(connect).transpose()?;
+    *LOGGER.lock().unwrap() = Logger { format, level, target, socket };
+    Ok(())
+}
+
+fn connect(path: &str) -> io::Result<Arc<UnixDatagram>> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot connect to {}: {}", path, e)))?;
+    socket.set_nonblocking(true)?;
+    Ok(Arc::new(socket))
+}
+
+// Sends a record to the syslog or journal socket, returns whether it was sent.  When the daemon
+// was restarted since the socket was connected, the socket is connected again.
+fn send(socket: Option<Arc<UnixDatagram>>, target: Target, message: &[u8]) -> bool {
+    let (socket, path) = match (socket, target.path()) {
+        (Some(socket), Some(path)) => (socket, path),
+        _ => return false,
+    };
+    match send_to(&socket, target, message) {
+        Ok(()) => true,
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ECONNREFUSED | libc::ENOTCONN | libc::ENOENT)) => {
+            let socket = match connect(path) {
+                Ok(socket) => socket,
+                Err(_) => return false,
+            };
+            let mut logger = LOGGER.lock().unwrap();
+            if logger.target == target {
+                logger.socket = Some(socket.clone());
+            }
+            drop(logger);
+            send_to(&socket, target, message).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+fn send_to(socket: &UnixDatagram, target: Target, message: &[u8]) -> io::Result<()> {
+    match socket.send(message) {
+        Ok(_) => Ok(()),
+        // The journal takes large records in a memfd instead
+        Err(e) if target == Target::Journald && e.raw_os_error() == Some(libc::EMSGSIZE) => {
+            sys::send_memfd(socket, message)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Forwards a line of task output, `stream` is either `stdout` or `stderr`.  With the stderr target
+// the line goes to the same stream of OCRON prefixed with the task name, or as an `output` record
+// with the JSON format.  Otherwise the task name is used as the syslog identifier and lines from
+// stderr get the warning severity.
+pub fn task_output(task: &str, stream: &str, line: &str) {
+    let (format, target, socket) = {
+        let logger = LOGGER.lock().unwrap();
+        (logger.format, logger.target, logger.socket.clone())
+    };
+    let level = output_level(stream);
+    let sent = match target {
+        Target::Stderr => false,
+        Target::Syslog => {
+            let message = syslog_message(level, task, "-", "-", line);
+            send(socket, target, message.as_bytes())
+        }
+        Target::Journald => {
+            let mut message = Vec::new();
+            journal_field(&mut message, "MESSAGE", line);
+            journal_field(&mut message, "PRIORITY", &level.severity().to_string());
+            journal_field(&mut message, "SYSLOG_IDENTIFIER", task);
+            journal_field(&mut message, "OCRON_TASK", task);
+            journal_field(&mut message, "OCRON_STREAM", stream);
+            send(socket, target, &message)
+        }
+    };
+    if !sent {
+        let line = match format {
+            Format::Text => format!("[{}] {}", task, line),
+            Format::Json => output_event(task, stream, line).json(),
+        };
+        // There is nowhere to report a failure to log
+        let _ = match stream {
+            "stdout" => writeln!(io::stdout(), "{}", line),
+            _ => writeln!(io::stderr(), "{}", line),
+        };
+    }
+}
+
+fn output_level(stream: &str) -> Level {
+    if stream == "stderr" { Level::Warn } else { Level::Info }
+}
+
+fn output_event(task: &str, stream: &str, line: &str) -> Event {
+    Event::new(output_level(stream), line).kind("output").task(task).field("stream", stream)
+}
+
+pub struct Event {
+    level: Level,
+    kind: &'static str,
+    task: Option<String>,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl Event {
+    pub fn new<M: fmt::Display>(level: Level, message: M) -> Event {
+        Event {
+            level,
+            kind: "message",
+            task: None,
+            message: message.to_string(),
+            fields: Map::new(),
+        }
+    }
+
+    pub fn error<M: fmt::Display>(message: M) -> Event {
+        Event::new(Level::Error, message)
+    }
+
+    pub fn warn<M: fmt::Display>(message: M) -> Event {
+        Event::new(Level::Warn, message)
+    }
+
+    pub fn info<M: fmt::Display>(message: M) -> Event {
+        Event::new(Level::Info, message)
+    }
+
+    pub fn debug<M: fmt::Display>(message: M) -> Event {
+        Event::new(Level::Debug, message)
+    }
+
+    // The kind of event, e.g. `started` or `finished`, `message` by default.
+    pub fn kind(mut self, kind: &'static str) -> Event {
+        self.kind = kind;
+        self
+    }
+
+    pub fn task(mut self, name: &str) -> Event {
+        self.task = Some(name.to_owned());
+        self
+    }
+
+    pub fn field<V: Into<Value>>(mut self, key: &str, value: V) -> Event {
+        self.fields.insert(key.to_owned(), value.into());
+        self
+    }
+
+    pub fn time(self, key: &str, time: DateTime<Utc>) -> Event {
+        self.field(key, time.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    pub fn duration(self, duration: Duration) -> Event {
+        self.field("duration", duration.as_secs_f64())
+    }
+
+    // Adds the exit code, or the signal which killed the process.
+    pub fn status(self, status: ExitStatus) -> Event {
+        self.field("exit_code", status.code())
+            .field("signal", status.signal())
+    }
+
+    fn json(&self) -> String {
+        let mut record = Map::new();
+        record.insert("time".into(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into());
+        record.insert("level".into(), self.level.to_string().into());
+        record.insert("event".into(), self.kind.into());
+        if let Some(task) = &self.task {
+            record.insert("task".into(), task.clone().into());
+        }
+        record.insert("message".into(), self.message.clone().into());
+        record.extend(self.fields.clone());
+        Value::Object(record).to_string()
+    }
+
+    pub fn emit(self) {
+        let (format, target, socket) = {
+            let logger = LOGGER.lock().unwrap();
+            if self.level > logger.level {
+                return;
+            }
+            (logger.format, logger.target, logger.socket.clone())
+        };
+
+        // Other levels are apparent from the message
+        let prefix = if self.level == Level::Error { "error: " } else { "" };
+        let text = match &self.task {
+            Some(task) => format!("[{}] {}{}", task, prefix, self.message),
+            None => format!("{}{}", prefix, self.message),
+        };
+        let line = match format {
+            Format::Text => text.clone(),
+            Format::Json => self.json(),
+        };
+        let sent = match target {
+            Target::Stderr => false,
+            Target::Syslog => {
+                let mut data = String::new();
+                if self.task.is_some() || !self.fields.is_empty() {
+                    data.push_str("[ocron@32473");
+                    if let Some(task) = &self.task {
+                        push_param(&mut data, "task", task);
+                    }
+                    for (key, value) in self.fields.iter() {
+                        match value {
+                            Value::Null => {}
+                            Value::String(string) => push_param(&mut data, key, string),
+                            value => push_param(&mut data, key, &value.to_string()),
+                        }
+                    }
+                    data.push(']');
+                } else {
+                    data.push('-');
+                }
+                let message = syslog_message(self.level, "ocron", self.kind, &data, &line);
+                send(socket, target, message.as_bytes())
+            }
+            Target::Journald => {
+                let mut message = Vec::new();
+                // The fields carry the structure, so the message stays plain text
+                journal_field(&mut message, "MESSAGE", &text);
+                journal_field(&mut message, "PRIORITY", &self.level.severity().to_string());
+                journal_field(&mut message, "SYSLOG_IDENTIFIER", "ocron");
+                journal_field(&mut message, "OCRON_EVENT", self.kind);
+                if let Some(task) = &self.task {
+                    journal_field(&mut message, "OCRON_TASK", task);
+                }
+                for (key, value) in self.fields.iter() {
+                    // Named after `EXIT_STATUS` of systemd's own records
+                    let key = match key.as_str() {
+                        "exit_code" => "EXIT_STATUS".to_owned(),
+                        key => key.to_uppercase(),
+                    };
+                    match value {
+                        Value::Null => {}
+                        Value::String(string) => journal_field(&mut message, &format!("OCRON_{}", key), string),
+                        value => journal_field(&mut message, &format!("OCRON_{}", key), &value.to_string()),
+                    }
+                }
+                send(socket, target, &message)
+            }
+        };
+        if !sent {
+            // There is nowhere to report a failure to log
+            let _ = writeln!(io::stderr(), "{}", line);
+        }
+    }
+}
+
+// Formats a message for the syslog socket according to RFC 5424, with the daemon facility.
+fn syslog_message(level: Level, app: &str, msgid: &str, data: &str, message: &str) -> String {
+    const FACILITY_DAEMON: u8 = 3;
+    // Fields can't contain spaces and are limited to printable ASCII
+    let field = |value: &str, max: usize| -> String {
+        let value: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+        if value.is_empty() { "-".to_owned() } else { value }
+    };
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        FACILITY_DAEMON * 8 + level.severity(),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        field(&sys::hostname().unwrap_or_default(), 255),
+        field(app, 48),
+        process::id(),
+        field(msgid, 32),
+        data,
+        message,
+    )
+}
+
+fn push_param(data: &mut String, key: &str, value: &str) {
+    let _ = write!(data, " {}=\"", key);
+    for c in value.chars() {
+        if let '"' | '\\' | ']' = c {
+            data.push('\\');
+        }
+        data.push(c);
+    }
+    data.push('"');
+}
+
+// Appends a field in the journal native protocol, values with newlines need the binary form.
+fn journal_field(message: &mut Vec<u8>, key: &str, value: &str) {
+    message.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+impl Target {
+    fn path(self) -> Option<&'static str> {
+        match self {
+            Target::Stderr => None,
+            Target::Syslog => Some(SYSLOG_SOCKET),
+            Target::Journald => Some(JOURNAL_SOCKET),
+        }
+    }
+}
+
+impl Level {
+    // Severity of the level in syslog and the journal.
+    fn severity(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        })
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn syslog_format() {
+    let mut data = String::from("[ocron@32473");
+    push_param(&mut data, "task", "a \"b\" [c] \\d");
+    data.push(']');
+    assert_eq!(data, r#"[ocron@32473 task="a \"b\" [c\] \\d"]"#);
+
+    let message = syslog_message(Level::Error, "my task", "", &data, "failed: exit status: 1");
+    let parts = message.splitn(7, ' ').collect::<Vec<_>>();
+    // The daemon facility with the error severity
+    assert_eq!(parts[0], "<27>1");
+    assert!(DateTime::parse_from_rfc3339(parts[1]).is_ok());
+    assert_eq!(parts[3], "mytask");
+    assert_eq!(parts[4], process::id().to_string());
+    assert_eq!(parts[5], "-");
+    assert_eq!(parts[6], format!("{} failed: exit status: 1", data));
+}
+
+#[cfg(test)]
+#[test]
+fn journal_format() {
+    let mut message = Vec::new();
+    journal_field(&mut message, "MESSAGE", "one line");
+    journal_field(&mut message, "OCRON_OUTPUT", "two\nlines");
+    let mut expected = b"MESSAGE=one line\nOCRON_OUTPUT\n".to_vec();
+    expected.extend_from_slice(&9u64.to_le_bytes());
+    expected.extend_from_slice(b"two\nlines\n");
+    assert_eq!(message, expected);
+
+    // With the stderr target and the JSON format task output is a record like any other
+    let record = serde_json::from_str::<Value>(&output_event("t", "stderr", "oops").json()).unwrap();
+    assert_eq!(record["event"], "output");
+    assert_eq!(record["level"], "warn");
+    assert_eq!(record["task"], "t");
+    assert_eq!(record["stream"], "stderr");
+    assert_eq!(record["message"], "oops");
+}
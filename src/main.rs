@@ -1,16 +1,34 @@
 #![cfg_attr(test, feature(test))]
 
+use crate::log::Event;
 use crate::queue::Queue;
+use crate::reaper::Reaper;
 use anyhow::{Context, Result};
 use chrono::prelude::*;
 use config::Config;
+use state::StateFile;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{env, mem, thread};
+use std::{env, fs, mem, process, thread};
 
+mod cgroup;
+mod check;
 mod config;
+mod ctl;
+mod log;
+mod metrics;
+mod next;
+mod output;
 mod task;
 mod queue;
+mod reaper;
+mod state;
+mod sys;
+mod zone;
 
 trait LogError<T> {
     fn log_error(self, task_name: &str) -> Option<T>;
@@ -21,77 +39,227 @@ impl<T, E: std::fmt::Display> LogError<T> for Result<T, E> {
         match self {
             Ok(ok) => Some(ok),
             Err(e) => {
-                eprintln!("[{}] error: {}", task_name, e);
+                Event::error(e).task(task_name).emit();
                 None
             }
         }
     }
 }
 
+const USAGE: &str = "\
+usage: ocron [--init] <config_file>
+       ocron check <config_file>
+       ocron next <config_file> [--count N] [--task NAME] [--from DATETIME] [--json]
+       ocron run <config_file> <task_name>
+       ocron ctl <config_file> list|status|reload
+       ocron ctl <config_file> trigger|pause|resume <task_name>";
+
 fn main() -> Result<()> {
-    let arg = env::args().nth(1)
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let arg = args.first()
         .context("missing argument <config_file>")?;
 
-    if matches!(arg.as_str(), "-h" | "--help" | "-help") {
-        eprintln!("usage: ocron <config_file>");
-        return Ok(());
+    match arg.as_str() {
+        "-h" | "--help" | "-help" => {
+            eprintln!("{}", USAGE);
+            Ok(())
+        }
+        "check" => {
+            let path = args.get(1)
+                .context("missing argument <config_file>")?;
+            check::check(path)
+        }
+        "next" => next::next(&args[1..]),
+        "ctl" => ctl::ctl(&args[1..]),
+        "run" => {
+            let path = args.get(1)
+                .context("missing argument <config_file>")?;
+            let name = args.get(2)
+                .context("missing argument <task_name>")?;
+            run(path, name)
+        }
+        "--init" => {
+            let path = args.get(1)
+                .context("missing argument <config_file>")?;
+            daemon(path, true)
+        }
+        // As PID 1 nobody else reaps orphans
+        _ => daemon(arg, process::id() == 1),
+    }
+}
+
+fn daemon(arg: &str, init: bool) -> Result<()> {
+    // Register signal handlers early, the default action of SIGHUP would kill us
+    let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT])
+        .context("registering signal handlers")?;
+    // Signals without a handler are ignored by PID 1, so SIGQUIT has to be handled as well
+    if init {
+        signals.add_signal(SIGQUIT)
+            .context("registering signal handlers")?;
     }
+    let reaper = if init {
+        Some(Reaper::start()?)
+    } else {
+        None
+    };
 
     // Parse config
     let mut config = Config::read_file(arg)?;
+    init_log(&config)?;
 
-    // Print debug info
+    // Log debug info
     if config.debug {
-        eprintln!("{:#?}", &config);
-        eprintln!();
+        Event::debug(format!("{:#?}", config)).kind("config").emit();
         for (key, val) in env::vars_os() {
-            eprintln!("{:?} = {:?}", key.to_string_lossy(), val.to_string_lossy());
+            Event::debug(format!("{:?} = {:?}", key.to_string_lossy(), val.to_string_lossy()))
+                .kind("env")
+                .emit();
         }
-        eprintln!();
     }
 
-    let queue = Queue::new();
+    let startup = startup_options(&config);
+    let state_file = config.state_file.as_ref()
+        .map(StateFile::open)
+        .transpose()?;
+    let queue = Queue::new(state_file, reaper);
+
+    if let Some(socket_path) = &config.control_socket {
+        ctl::serve(socket_path, arg, queue.clone(), startup.clone())?;
+    }
 
     // Start tasks
-    mem::take(&mut config.tasks)
-        .into_iter()
-        .try_for_each(|task| -> Result<()> {
-            let task = Arc::new(task);
-            if task.on_startup {
-                let now = Local::now().naive_local();
-                queue.notify_push(now, task);
-            } else {
-                let next = task.time.next_run()?;
-                queue.notify_push(next, task);
-            }
-            Ok(())
-        })
+    queue.load(mem::take(&mut config.tasks), true)
         .context("starting tasks")?;
 
+    if let Some(addr) = config.metrics_listen {
+        metrics::serve(addr, queue.clone())?;
+    }
+
+    // Reload config on SIGHUP, shut down on SIGTERM and SIGINT (and SIGQUIT in init mode), and
+    // kill the remaining tasks right away when the signal comes again.  In init mode the tasks
+    // get the signal OCRON received instead of `shutdown_signal`.
+    {
+        let queue = queue.clone();
+        let arg = arg.to_owned();
+        let shutdown_signal = config.shutdown_signal;
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => {
+                        if let Err(e) = reload(&arg, &queue, &startup) {
+                            Event::error(format!("{:#}", e)).emit();
+                        }
+                    }
+                    _ if queue.is_shutting_down() => {
+                        Event::warn(format!("received {} again, killing tasks", sys::signal_name(signal))).emit();
+                        queue.kill_runs();
+                    }
+                    _ => {
+                        Event::info(format!("received {}, shutting down", sys::signal_name(signal)))
+                            .kind("shutdown")
+                            .emit();
+                        queue.shutdown(if init { signal } else { shutdown_signal });
+                    }
+                }
+            }
+        });
+    }
+
     // Dispatch loop
     loop {
-        while queue.wait_peek_time() < Local::now().naive_local() {
-            queue.try_pop()
-                .unwrap()
-                .run(queue.clone());
+        while let Some(qt) = queue.pop_due(Utc::now()) {
+            qt.task.run(queue.clone(), qt.trigger, qt.attempt);
         }
 
+        let peek_time = match queue.wait_peek_time() {
+            Some(peek_time) => peek_time,
+            None => break,
+        };
+
         // Ord::clamp is unstable until Rust 1.50.0
         fn clamp<T: Ord>(x: T, min: T, max: T) -> T {
             assert!(min <= max);
             Ord::min(max, Ord::max(min, x))
         }
 
-        thread::sleep(
+        queue.wait(
+            peek_time,
             // In some pathologic cases when time-traveling ocron can get stuck asleep,
             // let's limit the maximum sleep duration to 60s.
             clamp(
-                (queue.wait_peek_time() - Local::now().naive_local())
+                (peek_time - Utc::now())
                     .to_std()
                     .unwrap_or(Duration::from_secs(1)) / 2,
                 Duration::from_secs(1),
                 Duration::from_secs(60),
-            )
+            ),
         );
     }
+
+    // Shutdown, the tasks were already signalled
+    let mut code = 0;
+    if !queue.wait_runs(config.shutdown_timeout.to_std().unwrap()) {
+        queue.kill_runs();
+        queue.wait_runs(Duration::from_secs(5));
+        code = 1;
+    }
+    if let Some(socket_path) = &config.control_socket {
+        if let Err(e) = fs::remove_file(socket_path) {
+            Event::error(format!("removing control socket {:?}: {}", socket_path, e)).emit();
+        }
+    }
+    Event::info("shut down").kind("exit").field("exit_code", code).emit();
+    process::exit(code);
+}
+
+// Runs a single task in the foreground and exits with its exit code.
+fn run(path: &str, name: &str) -> Result<()> {
+    let config = Config::read_file(path)?;
+    init_log(&config)?;
+    let task = config.tasks.iter()
+        .find(|task| task.name == name)
+        .with_context(|| format!("there is no task named `{}`", name))?;
+
+    let status = task.run_foreground()
+        .with_context(|| format!("running task `{}`", name))?;
+    // Like shells, report death by a signal as 128 + signal number
+    process::exit(status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)));
+}
+
+fn init_log(config: &Config) -> Result<()> {
+    let level = if config.debug { log::Level::Debug } else { config.log_level };
+    log::init(config.log_format, level, config.log_target)
+        .context("opening log target")
+}
+
+// Reloads the config, `startup` are the options of the config OCRON started with which a reload
+// can't change.
+fn reload<P: AsRef<Path>>(path: P, queue: &Arc<Queue>, startup: &[(&str, String)]) -> Result<()> {
+    let path = path.as_ref();
+    Event::info(format!("reloading config {:?}", path)).emit();
+    let mut config = Config::read_file(path)
+        .context("keeping the old config, reload failed")?;
+    queue.load(mem::take(&mut config.tasks), false)
+        .context("keeping the old config, reload failed")?;
+    for ((name, old), (_, new)) in startup.iter().zip(startup_options(&config)) {
+        if *old != new {
+            Event::warn(format!("global `{}` changed, it takes effect only after restarting ocron", name)).emit();
+        }
+    }
+    // The tasks are reloaded already, so only the logging stays as it was
+    init_log(&config)
+        .context("keeping the old log options")?;
+    Event::info("config reloaded").kind("reloaded").emit();
+    Ok(())
+}
+
+// Globals which take effect only at startup by name, with their values formatted for comparison.
+fn startup_options(config: &Config) -> Vec<(&'static str, String)> {
+    vec![
+        ("state_file", format!("{:?}", config.state_file)),
+        ("control_socket", format!("{:?}", config.control_socket)),
+        ("metrics_listen", format!("{:?}", config.metrics_listen)),
+        ("shutdown_signal", format!("{:?}", config.shutdown_signal)),
+        ("shutdown_timeout", format!("{:?}", config.shutdown_timeout)),
+    ]
 }
@@ -0,0 +1,249 @@
+use crate::log::Event;
+use crate::queue::Queue;
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use std::collections::HashMap as Map;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Upper bounds of the run duration histogram buckets in seconds.
+const BUCKETS: [f64; 10] = [0.1, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0];
+
+// Counters of finished runs by task name, the rest of the metrics is taken from the queue.
+#[derive(Default)]
+pub struct Metrics {
+    tasks: Mutex<Map<String, TaskMetrics>>,
+}
+
+#[derive(Default)]
+struct TaskMetrics {
+    runs: u64,
+    failures: u64,
+    timeouts: u64,
+    spawn_errors: u64,
+    // Cumulative counts of runs not longer than the corresponding bucket bound
+    buckets: [u64; BUCKETS.len()],
+    duration_count: u64,
+    duration_sum: f64,
+    last_success: Option<DateTime<Utc>>,
+    last_failure: Option<DateTime<Utc>>,
+}
+
+impl Metrics {
+    pub fn record_start(&self, name: &str) {
+        self.tasks.lock().unwrap().entry(name.to_owned()).or_default().runs += 1;
+    }
+
+    pub fn record_spawn_error(&self, name: &str) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = tasks.entry(name.to_owned()).or_default();
+        task.spawn_errors += 1;
+        task.last_failure = Some(Utc::now());
+    }
+
+    pub fn record_finish(&self, name: &str, success: bool, timed_out: bool, duration: Duration) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = tasks.entry(name.to_owned()).or_default();
+        let seconds = duration.as_secs_f64();
+        for (count, bound) in task.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        task.duration_count += 1;
+        task.duration_sum += seconds;
+        if timed_out {
+            task.timeouts += 1;
+        }
+        if success {
+            task.last_success = Some(Utc::now());
+        } else {
+            task.failures += 1;
+            task.last_failure = Some(Utc::now());
+        }
+    }
+
+    // Renders the metrics of the loaded tasks in the Prometheus text exposition format.
+    fn render(&self, queue: &Queue) -> String {
+        let status = queue.status();
+        let tasks = self.tasks.lock().unwrap();
+        let empty = TaskMetrics::default();
+        let mut out = String::new();
+
+        let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&str, &TaskMetrics) -> Vec<String>| {
+            let _ = writeln!(out, "# HELP ocron_task_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE ocron_task_{} {}", name, kind);
+            for task in status.iter() {
+                let label = format!("task=\"{}\"", escape(&task.task.name));
+                for line in value(&label, tasks.get(&task.task.name).unwrap_or(&empty)) {
+                    let _ = writeln!(out, "ocron_task_{}", line);
+                }
+            }
+        };
+
+        family("runs_total", "counter", "Runs started.", &|label, m| {
+            vec![format!("runs_total{{{}}} {}", label, m.runs)]
+        });
+        family("failures_total", "counter", "Runs which exited unsuccessfully or timed out.", &|label, m| {
+            vec![format!("failures_total{{{}}} {}", label, m.failures)]
+        });
+        family("timeouts_total", "counter", "Runs which timed out.", &|label, m| {
+            vec![format!("timeouts_total{{{}}} {}", label, m.timeouts)]
+        });
+        family("spawn_errors_total", "counter", "Runs which couldn't be started.", &|label, m| {
+            vec![format!("spawn_errors_total{{{}}} {}", label, m.spawn_errors)]
+        });
+        family("duration_seconds", "histogram", "Duration of finished runs.", &|label, m| {
+            let mut lines = BUCKETS.iter().zip(m.buckets.iter())
+                .map(|(bound, count)| format!("duration_seconds_bucket{{{},le=\"{}\"}} {}", label, bound, count))
+                .collect::<Vec<_>>();
+            lines.push(format!("duration_seconds_bucket{{{},le=\"+Inf\"}} {}", label, m.duration_count));
+            lines.push(format!("duration_seconds_sum{{{}}} {}", label, m.duration_sum));
+            lines.push(format!("duration_seconds_count{{{}}} {}", label, m.duration_count));
+            lines
+        });
+        family("last_success_timestamp_seconds", "gauge", "Time of the last successful run.", &|label, m| {
+            m.last_success.iter()
+                .map(|time| format!("last_success_timestamp_seconds{{{}}} {}", label, timestamp(*time)))
+                .collect()
+        });
+        family("last_failure_timestamp_seconds", "gauge", "Time of the last failed run.", &|label, m| {
+            m.last_failure.iter()
+                .map(|time| format!("last_failure_timestamp_seconds{{{}}} {}", label, timestamp(*time)))
+                .collect()
+        });
+
+        // Taken from the queue, not from the counters
+        let _ = writeln!(out, "# HELP ocron_task_running Runs currently in progress.");
+        let _ = writeln!(out, "# TYPE ocron_task_running gauge");
+        for task in status.iter() {
+            let _ = writeln!(out, "ocron_task_running{{task=\"{}\"}} {}", escape(&task.task.name), task.runs.len());
+        }
+        let _ = writeln!(out, "# HELP ocron_task_next_run_timestamp_seconds Time of the next scheduled run.");
+        let _ = writeln!(out, "# TYPE ocron_task_next_run_timestamp_seconds gauge");
+        for task in status.iter() {
+            if let Some(next_run) = task.next_run {
+                let _ = writeln!(
+                    out, "ocron_task_next_run_timestamp_seconds{{task=\"{}\"}} {}",
+                    escape(&task.task.name), timestamp(next_run),
+                );
+            }
+        }
+        out
+    }
+}
+
+// Starts a thread serving the metrics over HTTP at `addr`, each connection is handled in a thread
+// of its own so that a slow client doesn't hold up the others.
+pub fn serve(addr: SocketAddr, queue: Arc<Queue>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("binding metrics endpoint {}", addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let queue = queue.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &queue) {
+                            Event::error(format!("metrics endpoint: {:#}", e)).emit();
+                        }
+                    });
+                }
+                Err(e) => Event::error(format!("metrics endpoint: {}", e)).emit(),
+            }
+        }
+    });
+    Ok(())
+}
+
+// Answers a single request and closes the connection, `GET /metrics` is the only thing served.
+fn handle(mut stream: TcpStream, queue: &Queue) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    // The request line and headers are all that's read, so they're capped
+    let mut reader = BufReader::new((&stream).take(8192));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", queue.metrics.render(queue)),
+        (Some("GET"), _) => ("404 Not Found", "not found, metrics are at /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    )?;
+    Ok(())
+}
+
+fn timestamp(time: DateTime<Utc>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.0
+}
+
+// Escapes a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+#[test]
+fn exposition() {
+    use crate::config::Config;
+
+    let config = Config::parse(r#"
+        metrics_listen = "127.0.0.1:9100"
+        [[task]]
+        name = "a \"quoted\" task"
+        cmd = "true"
+        [[task]]
+        name = "idle"
+        cmd = "true"
+    "#).unwrap();
+    assert_eq!(config.metrics_listen, Some("127.0.0.1:9100".parse().unwrap()));
+    assert!(Config::parse("metrics_listen = \"localhost\"").is_err());
+
+    let queue = Queue::new(None, None);
+    queue.load(config.tasks, false).unwrap();
+    let name = "a \"quoted\" task";
+    for (success, seconds) in [(true, 0.5), (false, 20.0), (true, 4000.0)] {
+        queue.metrics.record_start(name);
+        queue.metrics.record_finish(name, success, !success, Duration::from_secs_f64(seconds));
+    }
+    queue.metrics.record_spawn_error(name);
+
+    let out = queue.metrics.render(&queue);
+    let label = r#"task="a \"quoted\" task""#;
+    let has = |line: String| out.lines().any(|l| l == line);
+    assert!(has(format!("ocron_task_runs_total{{{}}} 3", label)));
+    assert!(has(format!("ocron_task_failures_total{{{}}} 1", label)));
+    assert!(has(format!("ocron_task_timeouts_total{{{}}} 1", label)));
+    assert!(has(format!("ocron_task_spawn_errors_total{{{}}} 1", label)));
+    // Buckets are cumulative and the longest run only fits into +Inf
+    assert!(has(format!("ocron_task_duration_seconds_bucket{{{},le=\"0.1\"}} 0", label)));
+    assert!(has(format!("ocron_task_duration_seconds_bucket{{{},le=\"1\"}} 1", label)));
+    assert!(has(format!("ocron_task_duration_seconds_bucket{{{},le=\"30\"}} 2", label)));
+    assert!(has(format!("ocron_task_duration_seconds_bucket{{{},le=\"3600\"}} 2", label)));
+    assert!(has(format!("ocron_task_duration_seconds_bucket{{{},le=\"+Inf\"}} 3", label)));
+    assert!(has(format!("ocron_task_duration_seconds_sum{{{}}} 4020.5", label)));
+    assert!(has(format!("ocron_task_duration_seconds_count{{{}}} 3", label)));
+    assert!(has(format!("ocron_task_running{{{}}} 0", label)));
+
+    // Tasks which never ran have zero counters and no timestamps
+    assert!(has("ocron_task_runs_total{task=\"idle\"} 0".to_owned()));
+    assert!(!out.contains("last_success_timestamp_seconds{task=\"idle\"}"));
+    assert_eq!(out.matches("# TYPE ocron_task_runs_total counter").count(), 1);
+
+    assert_eq!(escape("a\\b\"c\nd"), r#"a\\b\"c\nd"#);
+}
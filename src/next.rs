@@ -0,0 +1,115 @@
+use crate::config::Config;
+use crate::zone::Zone;
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use serde_json::json;
+
+// Prints the upcoming run times of tasks.
+//
+// Runs of `after` tasks are computed as if each run finished instantly.
+pub fn next(args: &[String]) -> Result<()> {
+    let mut path = None;
+    let mut count = 5;
+    let mut task_name = None;
+    let mut from = None;
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" => {
+                count = args.next()
+                    .context("missing value of `--count`")?
+                    .parse::<usize>()
+                    .context("parsing `--count`")?;
+            }
+            "--task" => {
+                task_name = Some(args.next().context("missing value of `--task`")?);
+            }
+            "--from" => {
+                let string = args.next().context("missing value of `--from`")?;
+                from = Some(parse_datetime(string).context("parsing `--from`")?);
+            }
+            "--json" => {
+                json = true;
+            }
+            _ if path.is_none() && !arg.starts_with('-') => {
+                path = Some(arg);
+            }
+            _ => bail!("unexpected argument `{}`", arg),
+        }
+    }
+
+    let path = path.context("missing argument <config_file>")?;
+    let config = Config::read_file(path)?;
+    let from = from.unwrap_or_else(Utc::now);
+
+    let tasks = config.tasks.iter()
+        .filter(|task| task_name.is_none() || task_name == Some(&task.name))
+        .collect::<Vec<_>>();
+    if let (Some(name), true) = (task_name, tasks.is_empty()) {
+        bail!("there is no task named `{}`", name);
+    }
+
+    let mut schedule = Vec::new();
+    for task in tasks.into_iter() {
+        let mut runs = Vec::with_capacity(count);
+        let mut time = from;
+        while runs.len() < count {
+            match task.next_run_after(time)
+                .with_context(|| format!("computing next runs of task `{}`", task.name))? {
+                Some(next) => time = next,
+                None => break,
+            }
+            runs.push(time);
+        }
+        schedule.push((task, runs));
+    }
+
+    if json {
+        let tasks = schedule.iter()
+            .map(|(task, runs)| json!({
+                "name": task.name,
+                "timezone": task.timezone.name(),
+                "runs": runs.iter()
+                    .map(|&time| task.timezone.to_fixed(time).to_rfc3339())
+                    .collect::<Vec<_>>(),
+            }))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&tasks)?);
+    } else {
+        for (task, runs) in schedule.iter() {
+            if task.time.is_none() {
+                println!("[{}] runs only when triggered", task.name);
+            }
+            for &time in runs.iter() {
+                let weekday = task.timezone.to_local(time).weekday();
+                println!("[{}] {} {}", task.name, task.timezone.format(time), weekday);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Parses a date and time in the system's time zone.
+fn parse_datetime(string: &str) -> Result<DateTime<Utc>> {
+    let local = parse_naive_datetime(string)?;
+    Zone::local()
+        .to_utc(local)
+        .earliest()
+        .with_context(|| format!("`{}` doesn't exist in the local time zone", string))
+}
+
+fn parse_naive_datetime(string: &str) -> Result<NaiveDateTime> {
+    const FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+    for format in FORMATS.iter() {
+        if let Ok(time) = NaiveDateTime::parse_from_str(string, format) {
+            return Ok(time);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(string, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap());
+    }
+    bail!("invalid date and time `{}`, expected format like `2021-01-31 13:30:00`", string)
+}
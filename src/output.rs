@@ -0,0 +1,260 @@
+use crate::config::{Input, Output, OutputFile, Task};
+use crate::log::{self, Event};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command as Subprocess, Stdio};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+// Files with rotation which are currently written to.  Runs writing to the same path share the
+// file, so they don't rotate it from under each other.
+static FILES: Mutex<Vec<(PathBuf, Weak<Mutex<LogFile>>)>> = Mutex::new(Vec::new());
+
+// Standard streams of a run which have to be forwarded from or to a pipe by ocron.
+pub struct Capture {
+    name: String,
+    stdin: Option<String>,
+    stdout: Option<Sink>,
+    stderr: Option<Sink>,
+}
+
+#[derive(Clone)]
+enum Sink {
+    Stdout,
+    Stderr,
+    File(Arc<Mutex<LogFile>>),
+}
+
+impl Capture {
+    // Redirects stdin, stdout and stderr of the command as configured for the task.
+    pub fn attach(task: &Task, command: &mut Subprocess) -> Result<Capture> {
+        let date = task.timezone.to_local(Utc::now()).format("%Y-%m-%d").to_string();
+
+        let (stdin, stdin_text) = stdin(task)?;
+        command.stdin(stdin);
+
+        let (stdout, stdout_sink) = open(task, &task.stdout, &date, Sink::Stdout)
+            .context("opening task stdout")?;
+        let (stderr, stderr_sink) = open(task, &task.stderr, &date, Sink::Stderr)
+            .context("opening task stderr")?;
+        command.stdout(stdout).stderr(stderr);
+
+        Ok(Capture {
+            name: task.name.clone(),
+            stdin: stdin_text,
+            stdout: stdout_sink,
+            stderr: stderr_sink,
+        })
+    }
+
+    // Redirects only stdin of the command, its outputs stay inherited.
+    pub fn attach_stdin(task: &Task, command: &mut Subprocess) -> Result<Capture> {
+        let (stdin, stdin_text) = stdin(task)?;
+        command.stdin(stdin);
+        Ok(Capture {
+            name: task.name.clone(),
+            stdin: stdin_text,
+            stdout: None,
+            stderr: None,
+        })
+    }
+
+    // Starts feeding stdin and forwarding the piped outputs of the spawned child.  The threads finish on
+    // their own once every process holding the pipe exits.
+    pub fn start(self, child: &mut Child) {
+        if let (Some(text), Some(mut pipe)) = (self.stdin, child.stdin.take()) {
+            let name = self.name.clone();
+            thread::spawn(move || {
+                // The task doesn't have to read its stdin whole, so a broken pipe is fine
+                match pipe.write_all(text.as_bytes()) {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                        Event::error(format!("writing task stdin: {}", e))
+                            .task(&name)
+                            .emit();
+                    }
+                    _ => {}
+                }
+            });
+        }
+        if let (Some(sink), Some(pipe)) = (self.stdout, child.stdout.take()) {
+            forward(self.name.clone(), pipe, sink);
+        }
+        if let (Some(sink), Some(pipe)) = (self.stderr, child.stderr.take()) {
+            forward(self.name, pipe, sink);
+        }
+    }
+}
+
+fn stdin(task: &Task) -> Result<(Stdio, Option<String>)> {
+    Ok(match &task.stdin {
+        Input::Inherit => (Stdio::inherit(), None),
+        Input::Null => (Stdio::null(), None),
+        Input::File(path) => {
+            let file = File::open(path)
+                .with_context(|| format!("cannot open task stdin {:?}", path))?;
+            (Stdio::from(file), None)
+        }
+        Input::Text(text) => (Stdio::piped(), Some(text.clone())),
+    })
+}
+
+fn open(task: &Task, output: &Output, date: &str, log: Sink) -> Result<(Stdio, Option<Sink>)> {
+    match output {
+        Output::Inherit => Ok((Stdio::inherit(), None)),
+        Output::Null => Ok((Stdio::null(), None)),
+        Output::Log => Ok((Stdio::piped(), Some(log))),
+        Output::File(OutputFile { path, max_size: None, .. }) => {
+            let path = path.replace("{name}", &task.name).replace("{date}", date);
+            let file = open_append(Path::new(&path))
+                .with_context(|| format!("cannot open {:?}", path))?;
+            Ok((Stdio::from(file), None))
+        }
+        Output::File(OutputFile { path, max_size: Some(max_size), keep }) => {
+            let path = PathBuf::from(path.replace("{name}", &task.name).replace("{date}", date));
+            let file = LogFile::shared(path.clone(), *max_size, *keep)
+                .with_context(|| format!("cannot open {:?}", path))?;
+            Ok((Stdio::piped(), Some(Sink::File(file))))
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Copies the output line by line, so rotation never splits a line.
+fn forward<R: Read + Send + 'static>(name: String, pipe: R, sink: Sink) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        let mut failed = false;
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    Event::error(format!("reading task output: {}", e)).task(&name).emit();
+                    break;
+                }
+            }
+
+            match &sink {
+                Sink::Stdout => {
+                    log::task_output(&name, "stdout", String::from_utf8_lossy(&line).trim_end_matches('\n'));
+                }
+                Sink::Stderr => {
+                    log::task_output(&name, "stderr", String::from_utf8_lossy(&line).trim_end_matches('\n'));
+                }
+                // Keep draining the pipe after an error, so the task doesn't block on a full pipe
+                Sink::File(file) if !failed => {
+                    let mut file = file.lock().unwrap();
+                    if let Err(e) = file.write(&line) {
+                        Event::error(format!("writing task output to {:?}: {}", file.path, e))
+                            .task(&name)
+                            .emit();
+                        failed = true;
+                    }
+                }
+                Sink::File(_) => {}
+            }
+        }
+    });
+}
+
+// Output file which is rotated when it grows over `max_size`, the rotated files are renamed to
+// `path.1`, `path.2`, ... up to `path.<keep>`, the oldest ones get deleted.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl LogFile {
+    fn shared(path: PathBuf, max_size: u64, keep: u32) -> io::Result<Arc<Mutex<LogFile>>> {
+        let mut files = FILES.lock().unwrap();
+        files.retain(|(_, file)| file.strong_count() > 0);
+        if let Some(file) = files.iter().find(|(p, _)| *p == path).and_then(|(_, file)| file.upgrade()) {
+            // The settings may have changed by a config reload since the file was opened
+            let mut open = file.lock().unwrap();
+            open.max_size = max_size;
+            open.keep = keep;
+            drop(open);
+            return Ok(file);
+        }
+
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        let file = Arc::new(Mutex::new(LogFile { path: path.clone(), file, size, max_size, keep }));
+        files.push((path, Arc::downgrade(&file)));
+        Ok(file)
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: u32| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn log_file_rotation() {
+    use std::process;
+
+    let dir = std::env::temp_dir().join(format!("ocron-rotation-{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    let path = dir.join("task.log");
+    let read = |suffix: &str| {
+        let mut file = path.clone().into_os_string();
+        file.push(suffix);
+        fs::read_to_string(file).ok()
+    };
+
+    let file = LogFile::shared(path.clone(), 100, 5).unwrap();
+    // Runs writing to the same path share the file, with the settings of the latest one
+    assert!(Arc::ptr_eq(&file, &LogFile::shared(path.clone(), 10, 2).unwrap()));
+
+    // Lines are never split, a file is rotated before a line which wouldn't fit
+    for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+        file.lock().unwrap().write(line.as_bytes()).unwrap();
+    }
+    assert_eq!(read("").as_deref(), Some("four\nfive\n"));
+    assert_eq!(read(".1").as_deref(), Some("three\n"));
+    assert_eq!(read(".2").as_deref(), Some("one\ntwo\n"));
+    assert_eq!(read(".3"), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,23 +1,121 @@
-use crate::config::Task;
+use crate::config::{Overlap, Task, Time};
+use crate::log::Event;
+use crate::metrics::Metrics;
+use crate::reaper::Reaper;
+use crate::state::StateFile;
+use crate::sys;
+use crate::task::signal_group;
+use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap as Map, HashSet as Set};
+use std::fmt;
 use std::sync::{Arc, Condvar, Mutex};
+use std::{mem, thread};
 
 pub struct Queue {
-    queue: Mutex<BinaryHeap<QueuedTask>>,
+    state: Mutex<State>,
     condvar: Condvar,
+    pub state_file: Option<StateFile>,
+    // Set in init mode, runs are spawned and waited for through it
+    pub reaper: Option<Arc<Reaper>>,
+    pub metrics: Metrics,
+}
+
+#[derive(Default)]
+struct State {
+    queue: BinaryHeap<QueuedTask>,
+    // Tasks of the currently loaded config by name.  Anything else is a leftover from before
+    // a reload and must not get scheduled again.
+    tasks: Map<String, Arc<Task>>,
+    // Names of tasks whose scheduled runs are skipped.
+    paused: Set<String>,
+    // Runs which are currently in progress.
+    runs: Vec<Run>,
+    // Names of tasks with a run waiting for the previous one to finish, there is at most one.
+    queued: Set<String>,
+    last_run_id: u64,
+    // Signal sent to runs once shutdown started, nothing new is started then
+    shutdown: Option<libc::c_int>,
+}
+
+struct Run {
+    id: u64,
+    name: String,
+    // Unset until the child is spawned
+    pid: Option<u32>,
+    // Set before the child is reaped, its pid may be reused afterwards
+    exited: bool,
+    start: DateTime<Utc>,
+}
+
+// Snapshot of a task's state for the control socket.
+pub struct TaskStatus {
+    pub task: Arc<Task>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub paused: bool,
+    // Pids and start times of the runs in progress, the pid is `None` until spawned
+    pub runs: Vec<(Option<u32>, DateTime<Utc>)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    // Run according to the task's timing, which schedules the next run
+    Schedule,
+    // One-off run requested through the control socket
+    Manual,
+    // Run following another task's run through `on_success` or `on_failure`
+    Chain,
+}
+
+impl Run {
+    // Signals the process group of the run unless it wasn't spawned yet or has exited already,
+    // returns whether it was signalled.
+    fn signal(&self, signal: libc::c_int) -> bool {
+        match self.pid {
+            Some(pid) if !self.exited => {
+                signal_group(pid, signal);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) if self.exited => write!(f, "pid {}, exited", pid),
+            Some(pid) => write!(f, "pid {}", pid),
+            None => write!(f, "not spawned yet"),
+        }
+    }
+}
+
+impl State {
+    fn is_current(&self, task: &Arc<Task>) -> bool {
+        self.tasks
+            .get(&task.name)
+            .is_some_and(|current| Arc::ptr_eq(current, task))
+    }
+
+    fn runs_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Run> + 'a {
+        self.runs.iter().filter(move |run| run.name == name)
+    }
 }
 
 #[derive(Clone)]
 pub struct QueuedTask {
-    pub time: NaiveDateTime,
+    pub time: DateTime<Utc>,
     pub task: Arc<Task>,
+    pub trigger: Trigger,
+    // Starts at 1, higher for retries of failed runs
+    pub attempt: u32,
 }
 
 impl PartialEq for QueuedTask {
     fn eq(&self, other: &Self) -> bool {
-        NaiveDateTime::eq(&self.time, &other.time)
+        DateTime::eq(&self.time, &other.time)
     }
 }
 
@@ -31,36 +129,487 @@ impl PartialOrd for QueuedTask {
 
 impl Ord for QueuedTask {
     fn cmp(&self, other: &Self) -> Ordering {
-        NaiveDateTime::cmp(&self.time, &other.time).reverse()
+        DateTime::cmp(&self.time, &other.time).reverse()
     }
 }
 
+fn print_next_run(task: &Task, time: DateTime<Utc>) {
+    Event::info(format!("next run {}", task.timezone.format(time)))
+        .kind("scheduled")
+        .task(&task.name)
+        .time("next_run", time)
+        .emit();
+}
+
 impl Queue {
-    pub fn new() -> Arc<Queue> {
+    pub fn new(state_file: Option<StateFile>, reaper: Option<Arc<Reaper>>) -> Arc<Queue> {
         Arc::new(Queue {
-            queue: Mutex::default(),
+            state: Mutex::default(),
             condvar: Condvar::default(),
+            state_file,
+            reaper,
+            metrics: Metrics::default(),
         })
     }
 
-    pub fn notify_push(self: &Arc<Self>, time: NaiveDateTime, task: Arc<Task>) {
-        eprintln!("[{}] next run {:}", &task.name, time.format("%Y-%m-%d %H:%M:%S"));
-        self.queue.lock().unwrap().push(QueuedTask { time, task });
+    // Replaces the loaded tasks with `tasks`, matching them by name.
+    //
+    // Unchanged tasks keep their queued time (or keep running when they're running right now),
+    // removed tasks are dropped and new or modified tasks are scheduled from scratch.  `startup`
+    // makes tasks with `on_startup`, or with `catch_up` which missed a run, run immediately.
+    //
+    // When scheduling any of the tasks fails the loaded tasks are left untouched.
+    pub fn load(self: &Arc<Self>, tasks: Vec<Task>, startup: bool) -> Result<()> {
+        let now = Utc::now();
+        let mut state = self.state.lock().unwrap();
+
+        let mut loaded = Map::new();
+        let mut scheduled = Vec::new();
+        for task in tasks.into_iter() {
+            match state.tasks.get(&task.name) {
+                Some(current) if **current == task => {
+                    loaded.insert(task.name.clone(), current.clone());
+                }
+                current => {
+                    // Tasks without timing run only when triggered
+                    let time = if task.time.is_none() {
+                        None
+                    } else if startup && task.on_startup {
+                        Some(now)
+                    } else if startup && task.catch_up && self.missed_run(&task, now)? {
+                        Event::info("catching up with a run missed while ocron wasn't running")
+                            .task(&task.name)
+                            .emit();
+                        Some(now)
+                    } else {
+                        task.next_run()
+                            .with_context(|| format!("scheduling task `{}`", task.name))?
+                    };
+                    if !startup {
+                        let change = if current.is_some() { "changed" } else { "added" };
+                        Event::info(format!("{} by config reload", change)).task(&task.name).emit();
+                    }
+                    let task = Arc::new(task);
+                    loaded.insert(task.name.clone(), task.clone());
+                    if let Some(time) = time {
+                        scheduled.push(QueuedTask { time, task, trigger: Trigger::Schedule, attempt: 1 });
+                    }
+                }
+            }
+        }
+
+        for name in state.tasks.keys() {
+            if !loaded.contains_key(name) {
+                Event::info("removed by config reload").task(name).emit();
+            }
+        }
+
+        state.tasks = loaded;
+        let State { paused, tasks, .. } = &mut *state;
+        paused.retain(|name| tasks.contains_key(name));
+        let queue = mem::take(&mut state.queue);
+        state.queue = queue.into_iter()
+            .filter(|qt| state.is_current(&qt.task))
+            .collect();
+        for qt in scheduled.into_iter() {
+            print_next_run(&qt.task, qt.time);
+            state.queue.push(qt);
+        }
+
+        self.condvar.notify_all();
+        Ok(())
+    }
+
+    // Checks whether the task should have run since its last recorded run.
+    fn missed_run(&self, task: &Task, now: DateTime<Utc>) -> Result<bool> {
+        let state = match self.state_file.as_ref().and_then(|state_file| state_file.get(&task.name)) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let last_run = match &task.time {
+            Some(Time::After { .. }) => state.last_finish.or(state.last_start),
+            Some(Time::Every { .. } | Time::On { .. }) => state.last_start,
+            None => None,
+        };
+        match last_run {
+            Some(last_run) => {
+                let next = task.next_run_after(last_run.with_timezone(&Utc))
+                    .with_context(|| format!("scheduling task `{}`", task.name))?;
+                Ok(next.is_some_and(|next| next < now))
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn notify_push(self: &Arc<Self>, time: DateTime<Utc>, task: Arc<Task>, trigger: Trigger) {
+        self.push(QueuedTask { time, task, trigger, attempt: 1 });
+    }
+
+    // Queues another attempt of a failed run.
+    pub fn notify_retry(self: &Arc<Self>, time: DateTime<Utc>, task: Arc<Task>, trigger: Trigger, attempt: u32) {
+        self.push(QueuedTask { time, task, trigger, attempt });
+    }
+
+    fn push(&self, qt: QueuedTask) {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown.is_some() {
+            return;
+        }
+        if !state.is_current(&qt.task) {
+            Event::info("not rescheduling, the task was changed or removed by config reload")
+                .task(&qt.task.name)
+                .emit();
+            return;
+        }
+        if qt.trigger == Trigger::Schedule && qt.attempt == 1 {
+            print_next_run(&qt.task, qt.time);
+        }
+        state.queue.push(qt);
+        self.condvar.notify_all();
+    }
+
+    // Queues a one-off run of the task called `name` to run immediately.
+    pub fn trigger(self: &Arc<Self>, name: &str) -> Result<()> {
+        let task = self.state.lock().unwrap()
+            .tasks.get(name)
+            .cloned()
+            .with_context(|| format!("there is no task named `{}`", name))?;
+        Event::info("triggered manually").task(name).emit();
+        self.notify_push(Utc::now(), task, Trigger::Manual);
+        Ok(())
+    }
+
+    // Queues the tasks chained to a finished run of `task` to run immediately.
+    pub fn trigger_chained(self: &Arc<Self>, task: &Task, success: bool) {
+        let (names, outcome) = match success {
+            true => (&task.on_success, "success"),
+            false => (&task.on_failure, "failure"),
+        };
+        for name in names.iter() {
+            // The chained task may have been removed by a config reload meanwhile
+            let chained = self.state.lock().unwrap().tasks.get(name).cloned();
+            match chained {
+                Some(chained) => {
+                    Event::info(format!("triggered by {} of [{}]", outcome, task.name))
+                        .task(name)
+                        .emit();
+                    self.notify_push(Utc::now(), chained, Trigger::Chain);
+                }
+                None => {
+                    Event::warn(format!("not triggering [{}], there is no such task anymore", name))
+                        .task(&task.name)
+                        .emit();
+                }
+            }
+        }
+    }
+
+    // Pauses or resumes scheduled runs of the task called `name`.
+    pub fn set_paused(&self, name: &str, paused: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.tasks.contains_key(name) {
+            bail!("there is no task named `{}`", name);
+        }
+        if paused {
+            state.paused.insert(name.to_owned());
+            Event::info("paused").task(name).emit();
+        } else {
+            state.paused.remove(name);
+            Event::info("resumed").task(name).emit();
+        }
+        Ok(())
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.state.lock().unwrap().paused.contains(name)
+    }
+
+    // Returns the status of all loaded tasks sorted by name.
+    pub fn status(&self) -> Vec<TaskStatus> {
+        let state = self.state.lock().unwrap();
+        let mut status = state.tasks.iter()
+            .map(|(name, task)| TaskStatus {
+                task: task.clone(),
+                // Retries don't count, they have the trigger of the failed run
+                next_run: state.queue.iter()
+                    .filter(|qt| &qt.task.name == name && qt.trigger == Trigger::Schedule && qt.attempt == 1)
+                    .map(|qt| qt.time)
+                    .min(),
+                paused: state.paused.contains(name),
+                runs: state.runs_of(name)
+                    .map(|run| (run.pid, run.start))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        status.sort_unstable_by(|a, b| a.task.name.cmp(&b.task.name));
+        status
+    }
+
+    // Waits for the queue to be non-empty and returns the time of the first task, `None` once
+    // shutdown started.
+    pub fn wait_peek_time(self: &Arc<Self>) -> Option<DateTime<Utc>> {
+        let mut state = self.state.lock().unwrap();
+        while state.queue.is_empty() && state.shutdown.is_none() {
+            state = self.condvar.wait(state).unwrap();
+        }
+        match state.shutdown {
+            Some(_) => None,
+            None => Some(state.queue.peek().unwrap().time),
+        }
+    }
+
+    // Sleeps for `timeout` unless the first task in the queue changes from `peek_time` or shutdown
+    // starts meanwhile.
+    pub fn wait(&self, peek_time: DateTime<Utc>, timeout: std::time::Duration) {
+        let state = self.state.lock().unwrap();
+        let _ = self.condvar.wait_timeout_while(state, timeout, |state| {
+            state.shutdown.is_none() && state.queue.peek().map(|qt| qt.time) == Some(peek_time)
+        });
+    }
+
+    // Pops the first task in the queue if it's due before `now`.
+    pub fn pop_due(&self, now: DateTime<Utc>) -> Option<QueuedTask> {
+        let mut state = self.state.lock().unwrap();
+        match state.queue.peek() {
+            Some(qt) if qt.time < now && state.shutdown.is_none() => state.queue.pop(),
+            _ => None,
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.lock().unwrap().shutdown.is_some()
+    }
+
+    // Stops starting new runs and sends `signal` to the process groups of the runs in progress,
+    // runs spawned later get it as soon as their pid is known.
+    pub fn shutdown(&self, signal: libc::c_int) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = Some(signal);
+        for run in state.runs.iter() {
+            Event::info(format!("interrupted by shutdown, sending {} ({})", sys::signal_name(signal), run))
+                .task(&run.name)
+                .emit();
+            run.signal(signal);
+        }
+        self.condvar.notify_all();
+    }
+
+    // Waits up to `timeout` for the runs in progress to finish, returns whether they did.
+    pub fn wait_runs(&self, timeout: std::time::Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.condvar.wait_timeout_while(state, timeout, |state| !state.runs.is_empty())
+            .unwrap();
+        state.runs.is_empty()
+    }
+
+    // Sends SIGKILL to the process groups of the runs in progress.
+    pub fn kill_runs(&self) {
+        let state = self.state.lock().unwrap();
+        for run in state.runs.iter() {
+            Event::warn(format!("still running, sending SIGKILL ({})", run)).task(&run.name).emit();
+            run.signal(libc::SIGKILL);
+        }
+    }
+
+    // Registers a new run of `task` while applying its overlap policy to the runs already in
+    // progress.  Returns the id of the run, or `None` when the run should be skipped.
+    pub fn start_run(self: &Arc<Self>, task: &Task) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown.is_some() {
+            Event::info("not starting, ocron is shutting down").task(&task.name).emit();
+            return None;
+        }
+        match task.overlap {
+            Overlap::Allow => {}
+            Overlap::Skip => {
+                if let Some(run) = state.runs_of(&task.name).next() {
+                    Event::info(format!("skipped, previous run ({}) is still running", run))
+                        .kind("skipped")
+                        .task(&task.name)
+                        .emit();
+                    return None;
+                }
+            }
+            Overlap::Queue => {
+                if state.runs_of(&task.name).next().is_some() {
+                    // Runs overrunning several periods don't pile up
+                    if state.queued.contains(&task.name) {
+                        Event::info("skipped, another run is already waiting for the previous one")
+                            .kind("skipped")
+                            .task(&task.name)
+                            .emit();
+                        return None;
+                    }
+                    Event::info("waiting for the previous run to finish").task(&task.name).emit();
+                    state.queued.insert(task.name.clone());
+                    while state.runs_of(&task.name).next().is_some() && state.shutdown.is_none() {
+                        state = self.condvar.wait(state).unwrap();
+                    }
+                    state.queued.remove(&task.name);
+                    if state.shutdown.is_some() {
+                        Event::info("not starting, ocron is shutting down").task(&task.name).emit();
+                        return None;
+                    }
+                }
+            }
+            Overlap::Replace => {
+                // A run which is still being spawned can't be signalled yet
+                let spawning = |state: &State| state.runs_of(&task.name).any(|run| run.pid.is_none());
+                if spawning(&state) {
+                    Event::info("waiting for the previous run to be spawned").task(&task.name).emit();
+                    while spawning(&state) && state.shutdown.is_none() {
+                        state = self.condvar.wait(state).unwrap();
+                    }
+                    if state.shutdown.is_some() {
+                        Event::info("not starting, ocron is shutting down").task(&task.name).emit();
+                        return None;
+                    }
+                }
+                for run in state.runs_of(&task.name) {
+                    if self.terminate_run(run, task) {
+                        Event::info(format!("replacing previous run ({})", run))
+                            .task(&task.name)
+                            .emit();
+                    }
+                }
+            }
+        }
+
+        state.last_run_id += 1;
+        let id = state.last_run_id;
+        let start = Utc::now();
+        state.runs.push(Run { id, name: task.name.clone(), pid: None, exited: false, start });
+        Some(id)
+    }
+
+    pub fn set_run_pid(&self, id: u64, pid: u32) {
+        let mut state = self.state.lock().unwrap();
+        let shutdown = state.shutdown;
+        if let Some(run) = state.runs.iter_mut().find(|run| run.id == id) {
+            run.pid = Some(pid);
+            if let Some(signal) = shutdown {
+                run.signal(signal);
+            }
+        }
         self.condvar.notify_all();
     }
 
-    pub fn wait_peek_time(self: &Arc<Self>) -> NaiveDateTime {
-        let mut queue_lock = self.queue.lock().unwrap();
-        while queue_lock.is_empty() {
-            queue_lock = self.condvar.wait(queue_lock).unwrap();
+    // Marks the process of a run as exited, it must be called before the process is reaped.
+    pub fn set_run_exited(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(run) = state.runs.iter_mut().find(|run| run.id == id) {
+            run.exited = true;
         }
-        queue_lock.peek().unwrap().time
     }
 
-    pub fn try_pop(&self) -> Option<Arc<Task>> {
-        self.queue
-            .lock().unwrap()
-            .pop()
-            .map(|qt| qt.task.clone())
+    // Signals the process group of a run which hasn't exited yet, returns whether it was signalled.
+    pub fn signal_run(&self, id: u64, signal: libc::c_int) -> bool {
+        let state = self.state.lock().unwrap();
+        state.runs.iter().any(|run| run.id == id && run.signal(signal))
+    }
+
+    pub fn finish_run(&self, id: u64) {
+        self.state.lock().unwrap().runs.retain(|run| run.id != id);
+        self.condvar.notify_all();
+    }
+
+    // Sends SIGTERM to the process group of a run and SIGKILL when it's still running after
+    // `kill_grace`, returns false when the run has already exited.
+    fn terminate_run(self: &Arc<Self>, run: &Run, task: &Task) -> bool {
+        if !run.signal(libc::SIGTERM) {
+            return false;
+        }
+
+        let queue = self.clone();
+        let id = run.id;
+        let grace = task.kill_grace.to_std().unwrap();
+        let name = task.name.clone();
+        thread::spawn(move || {
+            thread::sleep(grace);
+            let state = queue.state.lock().unwrap();
+            if let Some(run) = state.runs.iter().find(|run| run.id == id) {
+                if run.signal(libc::SIGKILL) {
+                    Event::warn(format!("previous run ({}) still running after SIGTERM, sent SIGKILL", run))
+                        .task(&name)
+                        .emit();
+                }
+            }
+        });
+        true
     }
 }
+
+#[cfg(test)]
+#[test]
+fn reload() {
+    use crate::config::Config;
+
+    let load = |queue: &Arc<Queue>, toml: &str| {
+        queue.load(Config::parse(toml).unwrap().tasks, false).unwrap();
+        queue.status()
+    };
+
+    let queue = Queue::new(None, None);
+    let before = load(&queue, r#"
+        [[task]]
+        name = "changed"
+        cmd = "true"
+        every = { hours = 1 }
+        [[task]]
+        name = "kept"
+        cmd = "true"
+        every = { hours = 1 }
+        [[task]]
+        name = "removed"
+        cmd = "true"
+        every = { hours = 1 }
+    "#);
+    queue.set_paused("kept", true).unwrap();
+    queue.set_paused("removed", true).unwrap();
+
+    let after = load(&queue, r#"
+        [[task]]
+        name = "added"
+        cmd = "true"
+        every = { hours = 1 }
+        [[task]]
+        name = "changed"
+        cmd = "true"
+        every = { hours = 2 }
+        [[task]]
+        name = "kept"
+        cmd = "true"
+        every = { hours = 1 }
+    "#);
+    let names = after.iter().map(|status| status.task.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["added", "changed", "kept"]);
+
+    // The unchanged task keeps its queued run and state, the changed one is scheduled anew
+    assert!(!Arc::ptr_eq(&before[0].task, &after[1].task));
+    assert!(after[1].next_run.unwrap() > before[0].next_run.unwrap());
+    assert!(Arc::ptr_eq(&before[1].task, &after[2].task));
+    assert_eq!(before[1].next_run, after[2].next_run);
+    assert!(after[2].paused);
+
+    // Nothing of the removed task is left behind
+    assert!(!queue.is_paused("removed"));
+    let state = queue.state.lock().unwrap();
+    assert_eq!(state.queue.len(), 3);
+    assert!(state.queue.iter().all(|qt| state.is_current(&qt.task)));
+}
+
+#[cfg(test)]
+#[test]
+fn status_next_run() {
+    use crate::config::Config;
+
+    let queue = Queue::new(None, None);
+    let config = Config::parse("[[task]]\nname = \"t\"\ncmd = \"true\"\nevery = { hours = 1 }").unwrap();
+    queue.load(config.tasks, false).unwrap();
+    let status = queue.status();
+    let next_run = status[0].next_run.unwrap();
+
+    // A pending retry sooner than the next scheduled run isn't reported as the next run
+    queue.notify_retry(Utc::now() + chrono::Duration::minutes(1), status[0].task.clone(), Trigger::Schedule, 2);
+    assert_eq!(queue.status()[0].next_run, Some(next_run));
+}
@@ -0,0 +1,106 @@
+use crate::log::Event;
+use crate::sys;
+use anyhow::{Context, Result};
+use signal_hook::consts::SIGCHLD;
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, Child, Command as Subprocess, ExitStatus};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// Reaps every exited child when OCRON runs as init, including orphans reparented to it.
+//
+// Tasks can't wait for their children themselves, the reaper could collect the exit status
+// first.  They spawn and wait through the reaper instead, which keeps the status of its own
+// children until they're waited for.
+pub struct Reaper {
+    // Children spawned through the reaper by pid
+    children: Mutex<HashMap<u32, Registered>>,
+    condvar: Condvar,
+}
+
+struct Registered {
+    // Called once the child has exited but before it's reaped, while its pid is still reserved
+    exited: Option<Box<dyn FnOnce() + Send>>,
+    // Set once the child is reaped
+    status: Option<ExitStatus>,
+}
+
+impl Reaper {
+    pub fn start() -> Result<Arc<Reaper>> {
+        // Orphans go to PID 1 otherwise, which isn't OCRON when started with `--init`
+        if process::id() != 1 {
+            sys::set_child_subreaper()
+                .context("becoming a child subreaper")?;
+        }
+        let mut signals = Signals::new([SIGCHLD])
+            .context("registering SIGCHLD handler")?;
+        let reaper = Arc::new(Reaper {
+            children: Mutex::default(),
+            condvar: Condvar::default(),
+        });
+
+        // Zombies which were there before the handler was registered don't get a signal
+        reaper.reap();
+        {
+            let reaper = reaper.clone();
+            thread::spawn(move || {
+                // Signals coalesce, so every signal reaps everything that has exited
+                for _ in signals.forever() {
+                    reaper.reap();
+                }
+            });
+        }
+        Ok(reaper)
+    }
+
+    // Spawns the command, the child is registered before the reaper can collect it.  `exited` is
+    // called when the child exits, before it's reaped.
+    pub fn spawn(&self, command: &mut Subprocess, exited: Box<dyn FnOnce() + Send>) -> io::Result<Child> {
+        let mut children = self.children.lock().unwrap();
+        let child = command.spawn()?;
+        children.insert(child.id(), Registered { exited: Some(exited), status: None });
+        Ok(child)
+    }
+
+    // Waits for a child spawned by `spawn` to exit.
+    pub fn wait(&self, pid: u32) -> ExitStatus {
+        let mut children = self.children.lock().unwrap();
+        loop {
+            if let Some(&Registered { status: Some(status), .. }) = children.get(&pid) {
+                children.remove(&pid);
+                return status;
+            }
+            children = self.condvar.wait(children).unwrap();
+        }
+    }
+
+    fn reap(&self) {
+        let mut children = self.children.lock().unwrap();
+        // Fails with ECHILD when there are no children at all
+        while let Ok(Some(pid)) = sys::peek_exited() {
+            if let Some(exited) = children.get_mut(&pid).and_then(|child| child.exited.take()) {
+                exited();
+            }
+            let mut status = 0;
+            if unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) } != pid as libc::pid_t {
+                break;
+            }
+            match children.get_mut(&pid) {
+                Some(child) => {
+                    child.status = Some(ExitStatus::from_raw(status));
+                    self.condvar.notify_all();
+                }
+                None => {
+                    Event::debug(format!("reaped orphaned process {}: {}", pid, ExitStatus::from_raw(status)))
+                        .kind("reaped")
+                        .field("pid", pid)
+                        .status(ExitStatus::from_raw(status))
+                        .emit();
+                }
+            }
+        }
+    }
+}
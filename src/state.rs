@@ -0,0 +1,142 @@
+use crate::log::Event;
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use std::collections::HashMap as Map;
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use toml::value::{Table, Value};
+
+// Record of the last run of each task which persists across restarts.
+//
+// The file is a TOML table with a subtable for each task.  It's rewritten after every change by
+// writing a temporary file and renaming it over the old one, so it never ends up half written.
+pub struct StateFile {
+    path: PathBuf,
+    tasks: Mutex<Map<String, TaskState>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TaskState {
+    pub last_start: Option<DateTime<Local>>,
+    pub last_finish: Option<DateTime<Local>>,
+    pub last_exit_code: Option<i32>,
+    pub last_signal: Option<i32>,
+}
+
+impl StateFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<StateFile> {
+        let path = path.as_ref();
+        let tasks = match fs::read(path) {
+            Ok(data) => {
+                let table = toml::from_slice::<Table>(&data)
+                    .context("parsing toml")?;
+                table.into_iter()
+                    .map(|(name, value)| {
+                        let state = parse_task_state(value)
+                            .with_context(|| format!("parsing state of task `{}`", name))?;
+                        Ok((name, state))
+                    })
+                    .collect::<Result<_>>()
+                    .with_context(|| format!("parsing state file {:?}", path))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Map::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("cannot read state file {:?}", path));
+            }
+        };
+
+        Ok(StateFile {
+            path: path.to_owned(),
+            tasks: Mutex::new(tasks),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<TaskState> {
+        self.tasks.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn record_start(&self, name: &str, time: DateTime<Local>) {
+        self.update(name, |state| state.last_start = Some(time));
+    }
+
+    pub fn record_finish(&self, name: &str, time: DateTime<Local>, status: Option<ExitStatus>) {
+        self.update(name, |state| {
+            state.last_finish = Some(time);
+            state.last_exit_code = status.and_then(|status| status.code());
+            state.last_signal = status.and_then(|status| status.signal());
+        });
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut TaskState)) {
+        let mut tasks = self.tasks.lock().unwrap();
+        f(tasks.entry(name.to_owned()).or_default());
+        if let Err(e) = self.save(&tasks) {
+            Event::error(format!("saving state file {:?}: {:#}", self.path, e)).emit();
+        }
+    }
+
+    fn save(&self, tasks: &Map<String, TaskState>) -> Result<()> {
+        let table = tasks.iter()
+            .map(|(name, state)| (name.clone(), Value::Table(task_state_table(state))))
+            .collect::<Table>();
+        let data = toml::to_string(&Value::Table(table))?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn task_state_table(state: &TaskState) -> Table {
+    let mut table = Table::new();
+    if let Some(time) = state.last_start {
+        table.insert("last_start".into(), Value::String(time.to_rfc3339_opts(SecondsFormat::Secs, false)));
+    }
+    if let Some(time) = state.last_finish {
+        table.insert("last_finish".into(), Value::String(time.to_rfc3339_opts(SecondsFormat::Secs, false)));
+    }
+    if let Some(code) = state.last_exit_code {
+        table.insert("last_exit_code".into(), Value::Integer(code.into()));
+    }
+    if let Some(signal) = state.last_signal {
+        table.insert("last_signal".into(), Value::Integer(signal.into()));
+    }
+    table
+}
+
+fn parse_task_state(value: Value) -> Result<TaskState> {
+    let table = match value {
+        Value::Table(table) => table,
+        _ => bail!("expected table, found `{:?}`", value),
+    };
+
+    let parse_time = |value: Value| -> Result<DateTime<Local>> {
+        match value {
+            Value::String(string) => Ok(DateTime::parse_from_rfc3339(&string)?.with_timezone(&Local)),
+            _ => bail!("expected string, found `{:?}`", value),
+        }
+    };
+    let parse_int = |value: Value| -> Result<i32> {
+        match value {
+            Value::Integer(int) => Ok(int as i32),
+            _ => bail!("expected integer, found `{:?}`", value),
+        }
+    };
+
+    let mut state = TaskState::default();
+    for (key, value) in table.into_iter() {
+        match key.as_str() {
+            "last_start" => state.last_start = Some(parse_time(value)?),
+            "last_finish" => state.last_finish = Some(parse_time(value)?),
+            "last_exit_code" => state.last_exit_code = Some(parse_int(value)?),
+            "last_signal" => state.last_signal = Some(parse_int(value)?),
+            _ => bail!("unknown key `{}`", key),
+        }
+    }
+    Ok(state)
+}
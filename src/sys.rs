@@ -0,0 +1,275 @@
+// Safe wrappers of the libc calls ocron needs which the standard library doesn't provide.
+
+use crate::config::{Ioprio, IoprioClass, Resource, Rlimit};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::{self, MaybeUninit};
+use std::os::raw::c_char;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::ptr;
+
+// Signals which can be given by name in the config.
+pub const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGALRM", libc::SIGALRM),
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGCONT", libc::SIGCONT),
+    ("SIGSTOP", libc::SIGSTOP),
+    ("SIGWINCH", libc::SIGWINCH),
+];
+
+pub fn signal_name(signal: libc::c_int) -> String {
+    SIGNALS.iter()
+        .find(|&&(_, s)| s == signal)
+        .map_or_else(|| format!("signal {}", signal), |(name, _)| name.to_string())
+}
+
+// Entry of the passwd database.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Passwd {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+    pub shell: String,
+}
+
+pub fn user_by_name(name: &str) -> io::Result<Option<Passwd>> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    getpw(|pwd, buf, len, result| unsafe { libc::getpwnam_r(name.as_ptr(), pwd, buf, len, result) })
+}
+
+pub fn user_by_uid(uid: u32) -> io::Result<Option<Passwd>> {
+    getpw(|pwd, buf, len, result| unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) })
+}
+
+fn getpw<F>(call: F) -> io::Result<Option<Passwd>>
+where
+    F: Fn(*mut libc::passwd, *mut c_char, usize, *mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = ptr::null_mut();
+        match call(pwd.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let pwd = unsafe { pwd.assume_init() };
+                let string = |ptr: *const c_char| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned();
+                return Ok(Some(Passwd {
+                    name: string(pwd.pw_name),
+                    uid: pwd.pw_uid,
+                    gid: pwd.pw_gid,
+                    home: string(pwd.pw_dir),
+                    shell: string(pwd.pw_shell),
+                }));
+            }
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+pub fn group_by_name(name: &str) -> io::Result<Option<u32>> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf = vec![0 as c_char; 1024];
+    loop {
+        let mut grp = MaybeUninit::<libc::group>::uninit();
+        let mut result = ptr::null_mut();
+        let errno = unsafe {
+            libc::getgrnam_r(name.as_ptr(), grp.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result)
+        };
+        match errno {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(unsafe { grp.assume_init() }.gr_gid)),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+// Returns the groups the user is a member of, including `gid`.
+pub fn group_list(name: &str, gid: u32) -> io::Result<Vec<u32>> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut groups = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // `count` now holds the number of groups, but some implementations don't update it
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+pub fn hostname() -> io::Result<String> {
+    let mut buf = [0 as c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // The name is truncated without a terminating null when it doesn't fit
+    buf[buf.len() - 1] = 0;
+    Ok(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+// Waits for the child `pid` to exit without reaping it, so its pid can't be reused meanwhile.
+pub fn wait_exited(pid: u32) -> io::Result<()> {
+    loop {
+        let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+        let options = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, info.as_mut_ptr(), options) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+// Returns the pid of any child which has exited, without reaping it.
+pub fn peek_exited() -> io::Result<Option<u32>> {
+    let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+    let options = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+    if unsafe { libc::waitid(libc::P_ALL, 0, info.as_mut_ptr(), options) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // With WNOHANG the pid stays zero when no child has exited yet
+    let pid = unsafe { info.assume_init().si_pid() };
+    Ok(if pid > 0 { Some(pid as u32) } else { None })
+}
+
+// Makes orphaned descendants reparent to the current process instead of PID 1.
+pub fn set_child_subreaper() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Passes `data` over the socket in a sealed memfd, the way the journal accepts records too large
+// for a datagram.
+pub fn send_memfd(socket: &UnixDatagram, data: &[u8]) -> io::Result<()> {
+    let name = CString::new("ocron-journal").unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)?;
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A message without data carrying just the descriptor
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = space as _;
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(header) as *mut libc::c_int, fd);
+        if libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// The functions below are meant to be called between fork and exec, so they must only make
+// async-signal-safe calls.
+
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_ioprio(ioprio: Ioprio) -> io::Result<()> {
+    // From linux/ioprio.h, which libc doesn't provide
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: u32 = 13;
+    let class = match ioprio.class {
+        IoprioClass::Realtime => 1,
+        IoprioClass::BestEffort => 2,
+        IoprioClass::Idle => 3,
+    };
+    let value = (class << IOPRIO_CLASS_SHIFT) | ioprio.level;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_rlimit(rlimit: Rlimit) -> io::Result<()> {
+    let resource = match rlimit.resource {
+        Resource::Nofile => libc::RLIMIT_NOFILE,
+        Resource::As => libc::RLIMIT_AS,
+        Resource::Cpu => libc::RLIMIT_CPU,
+        Resource::Core => libc::RLIMIT_CORE,
+        Resource::Nproc => libc::RLIMIT_NPROC,
+        Resource::Fsize => libc::RLIMIT_FSIZE,
+    };
+    let limit = libc::rlimit {
+        rlim_cur: rlimit.soft.unwrap_or(libc::RLIM_INFINITY),
+        rlim_max: rlimit.hard.unwrap_or(libc::RLIM_INFINITY),
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Writes `data` to an existing file.
+pub fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Switches the credentials of the current process.
+pub fn switch_user(uid: Option<u32>, gid: Option<u32>, groups: Option<&[u32]>) -> io::Result<()> {
+    unsafe {
+        if let Some(groups) = groups {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(gid) = gid {
+            if libc::setgid(gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,26 @@
-use crate::config::{Command, EnvVal, Task, Time};
-use crate::queue::Queue;
+use crate::cgroup::Cgroup;
+use crate::config::{Command, Dst, DstGap, DstRepeat, EnvVal, Task, Time};
+use crate::log::{Event, Level};
+use crate::output::Capture;
+use crate::queue::{Queue, Trigger};
+use crate::sys;
+use crate::zone::Zone;
 use crate::LogError;
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
-use chrono::Duration;
-use std::process::Command as Subprocess;
-use std::sync::Arc;
+use chrono::{Duration, LocalResult};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command as Subprocess, ExitStatus};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Instant;
 
 impl Task {
-    pub fn run(self: Arc<Self>, queue: Arc<Queue>) {
+    // Builds the command of the task with its environment, joining `cgroup` when given.
+    pub fn command(&self, cgroup: Option<&Cgroup>) -> Subprocess {
         let mut command = match &self.command {
             Command::Shell(script) => {
                 let mut c = Subprocess::new(&self.shell);
@@ -24,9 +35,20 @@ impl Task {
             }
         };
 
+        if let Some(cgroup) = cgroup {
+            cgroup.join(&mut command);
+        }
         if self.clear_env {
             command.env_clear();
         }
+        // Set before `env`, so it can override them
+        if let Some(user) = &self.user {
+            command
+                .env("HOME", &user.home)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name)
+                .env("SHELL", &user.shell);
+        }
         self.env.iter().for_each(|(key, val)| match val {
             EnvVal::Set(val) => {
                 command.env(key, val);
@@ -36,42 +58,450 @@ impl Task {
             }
         });
 
+        if let Some(workdir) = &self.workdir {
+            command.current_dir(workdir);
+        }
+        if let Some(umask) = self.umask {
+            unsafe {
+                command.pre_exec(move || {
+                    libc::umask(umask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+
+        // Limits are set before switching the user, who may not be allowed to raise them
+        if let Some(nice) = self.nice {
+            unsafe {
+                command.pre_exec(move || sys::set_nice(nice));
+            }
+        }
+        if let Some(ioprio) = self.ioprio {
+            unsafe {
+                command.pre_exec(move || sys::set_ioprio(ioprio));
+            }
+        }
+        if !self.rlimits.is_empty() {
+            let rlimits = self.rlimits.clone();
+            unsafe {
+                command.pre_exec(move || rlimits.iter().try_for_each(|&rlimit| sys::set_rlimit(rlimit)));
+            }
+        }
+
+        if self.user.is_some() || self.group.is_some() || self.supplementary_groups.is_some() {
+            let uid = self.user.as_ref().map(|user| user.uid);
+            let gid = self.group;
+            let groups = self.supplementary_groups.clone();
+            unsafe {
+                command.pre_exec(move || sys::switch_user(uid, gid, groups.as_deref()));
+            }
+        }
+
+        command
+    }
+
+    // Returns `None` for tasks without timing.
+    pub fn next_run(&self) -> Result<Option<DateTime<Utc>>> {
+        self.next_run_after(Utc::now())
+    }
+
+    pub fn next_run_after(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+        self.time.as_ref()
+            .map(|time| time.next_run_after(now, &self.timezone, self.dst))
+            .transpose()
+    }
+
+    // Runs the task once in the foreground with its outputs on the terminal, without scheduling anything.
+    pub fn run_foreground(&self) -> Result<ExitStatus> {
+        // Run ids of the daemon start at 1, so 0 doesn't clash with its cgroups
+        let cgroup = self.cgroup_root.as_ref()
+            .map(|root| Cgroup::create(root, self, 0))
+            .transpose()?;
+        let mut command = self.command(cgroup.as_ref());
+        command.env("OCRON_ATTEMPT", "1");
+        new_session(&mut command);
+        let status = Capture::attach_stdin(self, &mut command)
+            .and_then(|capture| {
+                let child = command.spawn()
+                    .with_context(|| format!("cannot run {:?}", command))?;
+                Event::info(format!("running: {:?}", command)).kind("started").task(&self.name).emit();
+                self.wait_foreground(capture, child)
+            });
+        let usage = cgroup
+            .map(|cgroup| {
+                let usage = cgroup.usage().to_string();
+                cgroup.remove(&self.name).map(|()| usage)
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let (status, timed_out, duration) = status?;
+        let failed = timed_out || !status.success();
+        let message = if timed_out { "timed out" } else { "finished" };
+        Event::new(if failed { Level::Warn } else { Level::Info }, format!("{}: {}{}", message, status, usage))
+            .kind(if failed { "failed" } else { "finished" })
+            .task(&self.name)
+            .status(status)
+            .field("timed_out", timed_out)
+            .duration(duration)
+            .emit();
+        Ok(status)
+    }
+
+    // Waits for a run started by `run_foreground` with the same timeout and cleanup as the daemon
+    // applies, returns the exit status, whether it timed out and how long it ran.
+    fn wait_foreground(&self, capture: Capture, mut child: Child) -> Result<(ExitStatus, bool, std::time::Duration)> {
+        let pid = child.id();
+        let start = Instant::now();
+        capture.start(&mut child);
+
+        // Once the child may be reaped its pid can be reused, so it's no longer signalled
+        let exited = Arc::new(Mutex::new(false));
+        let signal = {
+            let exited = exited.clone();
+            move |signal| {
+                let exited = exited.lock().unwrap();
+                if !*exited {
+                    signal_group(pid, signal);
+                }
+                !*exited
+            }
+        };
+        // The task has a session of its own, so the signals from the terminal are forwarded to it
+        let mut signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM])
+            .context("registering signal handlers")?;
+        let handle = signals.handle();
+        let forward = signal.clone();
+        let forwarder = thread::spawn(move || {
+            for received in signals.forever() {
+                forward(received);
+            }
+        });
+        let watchdog = self.timeout
+            .map(|timeout| Watchdog::start(self, timeout, signal));
+
+        sys::wait_exited(pid).log_error(&self.name);
+        *exited.lock().unwrap() = true;
+        let status = child.wait();
+        let timed_out = watchdog.is_some_and(Watchdog::stop);
+        let duration = start.elapsed();
+        handle.close();
+        forwarder.join().unwrap();
+        if self.kill_leftovers {
+            self.kill_leftovers(pid);
+        }
+        Ok((status.context("waiting for the task")?, timed_out, duration))
+    }
+
+    // Returns the delay before retrying a failed `attempt`, `None` when there are no attempts left.
+    fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        let retry = self.retry.as_ref().filter(|retry| attempt < retry.attempts)?;
+        let mut seconds = retry.delay.num_milliseconds() as f64 / 1000.0
+            * retry.backoff.powi(attempt as i32 - 1);
+        if let Some(max_delay) = retry.max_delay {
+            seconds = seconds.min(max_delay.num_milliseconds() as f64 / 1000.0);
+        }
+        // Keep huge backoffs from overflowing
+        seconds = seconds.min(i32::MAX as f64);
+        Some(Duration::milliseconds((seconds * 1000.0) as i64))
+    }
+
+    // Terminates processes left in the process group of a run after its main process exited, first
+    // with SIGTERM and if they're still running after `kill_grace` with SIGKILL.
+    fn kill_leftovers(&self, pgid: u32) {
+        if !group_exists(pgid) {
+            return;
+        }
+        Event::warn("sending SIGTERM to processes left in the process group")
+            .task(&self.name)
+            .emit();
+        signal_group(pgid, libc::SIGTERM);
+
+        let deadline = Instant::now() + self.kill_grace.to_std().unwrap();
+        while group_exists(pgid) {
+            if Instant::now() >= deadline {
+                Event::warn("processes left in the process group still running after SIGTERM, sending SIGKILL")
+                    .task(&self.name)
+                    .emit();
+                signal_group(pgid, libc::SIGKILL);
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+
+    // Creates the cgroup of a run, when configured, and spawns the command of the run in it.
+    fn spawn(&self, queue: &Arc<Queue>, run: u64, attempt: u32, cgroup: &mut Option<Cgroup>)
+        -> Result<(Subprocess, Capture, Child)>
+    {
+        if let Some(root) = &self.cgroup_root {
+            *cgroup = Some(Cgroup::create(root, self, run)?);
+        }
+        let mut command = self.command(cgroup.as_ref());
+        command.env("OCRON_ATTEMPT", attempt.to_string());
+        new_session(&mut command);
+        let capture = Capture::attach(self, &mut command)?;
+        let child = match &queue.reaper {
+            Some(reaper) => {
+                let queue = queue.clone();
+                reaper.spawn(&mut command, Box::new(move || queue.set_run_exited(run)))
+            }
+            None => command.spawn(),
+        };
+        let child = child.with_context(|| format!("cannot run {:?}", command))?;
+        Ok((command, capture, child))
+    }
+
+    pub fn run(self: Arc<Self>, queue: Arc<Queue>, trigger: Trigger, attempt: u32) {
         thread::spawn(move || {
-            eprintln!("[{}] running: {:?}", self.name, command);
+            let scheduled = trigger == Trigger::Schedule;
+            let mut retrying = false;
 
-            if let Time::On { .. } | Time::Every { .. } = &self.time {
-                self.time.next_run()
+            // Retries don't affect the schedule, it was already taken care of by the first attempt
+            if let (true, Some(Time::On { .. } | Time::Every { .. })) = (scheduled && attempt == 1, &self.time) {
+                self.next_run()
                     .log_error(&self.name)
-                    .map(|next| queue.notify_push(next, self.clone()));
+                    .flatten()
+                    .map(|next| queue.notify_push(next, self.clone(), Trigger::Schedule));
             }
 
-            command.spawn()
-                .log_error(&self.name)
-                .map(|mut child| {
-                    child.wait()
-                        .log_error(&self.name)
-                        .map(|status| eprintln!("[{}] finished: {}", &self.name, status));
-                });
+            if trigger != Trigger::Manual && queue.is_paused(&self.name) {
+                Event::info("paused, skipping run").kind("skipped").task(&self.name).emit();
+            } else if let Some(run) = queue.start_run(&self) {
+                if let Some(state_file) = &queue.state_file {
+                    state_file.record_start(&self.name, Local::now());
+                }
+
+                let mut timed_out = false;
+                let mut cgroup = None;
+                let status = self.spawn(&queue, run, attempt, &mut cgroup)
+                    .map_err(|e| {
+                        queue.metrics.record_spawn_error(&self.name);
+                        Event::error(format!("{:#}", e))
+                            .kind("spawn_error")
+                            .task(&self.name)
+                            .field("attempt", attempt)
+                            .emit();
+                    })
+                    .ok()
+                    .and_then(|(command, capture, mut child)| {
+                        let pid = child.id();
+                        let started = Utc::now();
+                        let start = Instant::now();
+                        let message = match &self.retry {
+                            Some(retry) if attempt > 1 => {
+                                format!("running attempt {} of {}: {:?}", attempt, retry.attempts, command)
+                            }
+                            _ => format!("running: {:?}", command),
+                        };
+                        Event::info(message)
+                            .kind("started")
+                            .task(&self.name)
+                            .field("pid", pid)
+                            .field("attempt", attempt)
+                            .emit();
+                        queue.metrics.record_start(&self.name);
+
+                        capture.start(&mut child);
+                        queue.set_run_pid(run, pid);
+                        let watchdog = self.timeout
+                            .map(|timeout| {
+                                let queue = queue.clone();
+                                Watchdog::start(&self, timeout, move |signal| queue.signal_run(run, signal))
+                            });
+                        let status = match &queue.reaper {
+                            Some(reaper) => Some(reaper.wait(child.id())),
+                            None => {
+                                // The child stays a zombie until it's marked as exited, so the
+                                // watchdog can't signal a reused pid
+                                sys::wait_exited(pid).log_error(&self.name);
+                                queue.set_run_exited(run);
+                                child.wait().log_error(&self.name)
+                            }
+                        };
+                        timed_out = watchdog.is_some_and(Watchdog::stop);
+                        let usage = cgroup.as_ref().map_or_else(String::new, |cgroup| cgroup.usage().to_string());
+                        // A run whose status couldn't be collected counts as failed
+                        let failed = timed_out || !status.is_some_and(|status| status.success());
+                        queue.metrics.record_finish(&self.name, !failed, timed_out, start.elapsed());
+                        if let Some(status) = status {
+                            let message = if timed_out {
+                                format!("timed out: {}{}", status, usage)
+                            } else {
+                                format!("finished: {}{}", status, usage)
+                            };
+                            Event::new(if failed { Level::Warn } else { Level::Info }, message)
+                                .kind(if failed { "failed" } else { "finished" })
+                                .task(&self.name)
+                                .field("pid", pid)
+                                .field("attempt", attempt)
+                                .status(status)
+                                .field("timed_out", timed_out)
+                                .duration(start.elapsed())
+                                .time("started", started)
+                                .time("finished", Utc::now())
+                                .emit();
+                        }
+                        if self.kill_leftovers {
+                            self.kill_leftovers(pid);
+                        }
+                        status
+                    });
+                if let Some(cgroup) = cgroup {
+                    cgroup.remove(&self.name)
+                        .map_err(|e| format!("{:#}", e))
+                        .log_error(&self.name);
+                }
 
-            if let Time::After { .. } = &self.time {
-                self.time.next_run()
+                if let Some(state_file) = &queue.state_file {
+                    state_file.record_finish(&self.name, Local::now(), status);
+                }
+                queue.finish_run(run);
+
+                let failed = timed_out || !status.is_some_and(|status| status.success());
+                if let Some(delay) = self.retry_delay(attempt).filter(|_| failed) {
+                    let time = Utc::now() + delay;
+                    Event::warn(format!("attempt {} failed, next attempt {}", attempt, self.timezone.format(time)))
+                        .kind("retry")
+                        .task(&self.name)
+                        .field("attempt", attempt)
+                        .time("next_attempt", time)
+                        .emit();
+                    queue.notify_retry(time, self.clone(), trigger, attempt + 1);
+                    retrying = true;
+                } else {
+                    queue.trigger_chained(&self, !failed);
+                }
+            }
+
+            // `after` tasks are rescheduled once the run succeeds or runs out of attempts
+            if let (true, Some(Time::After { .. })) = (scheduled && !retrying, &self.time) {
+                self.next_run()
                     .log_error(&self.name)
-                    .map(|next| queue.notify_push(next, self));
+                    .flatten()
+                    .map(|next| queue.notify_push(next, self, Trigger::Schedule));
             }
         });
     }
 }
 
+pub fn signal(pid: u32, signal: libc::c_int) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, signal);
+    }
+}
+
+// Gives the command its own session and process group, so everything it starts can be signalled at
+// once by `signal_group` with the pid of the task.
+fn new_session(command: &mut Subprocess) {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+// Signals every process in the process group `pgid`.
+pub fn signal_group(pgid: u32, signal: libc::c_int) {
+    unsafe {
+        libc::kill(-(pgid as libc::pid_t), signal);
+    }
+}
+
+fn group_exists(pgid: u32) -> bool {
+    unsafe { libc::kill(-(pgid as libc::pid_t), 0) == 0 }
+}
+
+// Terminates the process group of a running task when it exceeds its timeout, first with SIGTERM
+// and if it's still running after `kill_grace` with SIGKILL.  The group is signalled through
+// `signal`, which knows whether the process has exited already and returns false when it has.
+struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start<F>(task: &Task, timeout: Duration, signal: F) -> Watchdog
+    where
+        F: Fn(libc::c_int) -> bool + Send + 'static,
+    {
+        let name = task.name.clone();
+        let grace = task.kill_grace;
+        let (done, done_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if done_rx.recv_timeout(timeout.to_std().unwrap()) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            // It exited right at the timeout
+            if !signal(libc::SIGTERM) {
+                return false;
+            }
+            Event::warn(format!("timed out after {}s, sent SIGTERM", timeout.num_seconds()))
+                .task(&name)
+                .emit();
+
+            if done_rx.recv_timeout(grace.to_std().unwrap()) == Err(RecvTimeoutError::Timeout)
+                && signal(libc::SIGKILL)
+            {
+                Event::warn(format!("still running {}s after SIGTERM, sent SIGKILL", grace.num_seconds()))
+                    .task(&name)
+                    .emit();
+            }
+            true
+        });
+        Watchdog { done, thread }
+    }
+
+    // Stops the watchdog once the child has exited and returns whether it timed out.
+    fn stop(self) -> bool {
+        drop(self.done);
+        self.thread.join().unwrap()
+    }
+}
+
 impl Time {
-    pub fn next_run(&self) -> Result<NaiveDateTime> {
-        let now = Local::now().naive_local();
+    // `on` timings are matched against the local time in `zone`, `dst` decides what happens with
+    // local times skipped or repeated by transitions.
+    //
+    // Around transitions the order of local times doesn't have to match the order of instants, so
+    // the search goes through matching local times, starting from the earliest one which may still
+    // occur, until no later local time can happen sooner than the best instant found.
+    pub fn next_run_after(&self, now: DateTime<Utc>, zone: &Zone, dst: Dst) -> Result<DateTime<Utc>> {
         match self {
             Time::After { duration } |
             Time::Every { duration } => {
                 Ok(now + *duration)
             }
             Time::On { second, minute, hour, weekday, day, month } => {
-                find_next_datetime(now, second, minute, hour, weekday, day, month)
+                let mut best = None;
+                let mut local = zone.earliest_local(now);
+                loop {
+                    local = find_next_datetime(local, second, minute, hour, weekday, day, month)?;
+
+                    let instants = match zone.to_utc(local) {
+                        LocalResult::Single(time) => vec![time],
+                        LocalResult::None => match dst.gap {
+                            DstGap::Skip => vec![],
+                            DstGap::Next => vec![zone.gap_end(local)],
+                        },
+                        LocalResult::Ambiguous(first, second) => match dst.repeat {
+                            DstRepeat::First => vec![first],
+                            DstRepeat::Both => vec![first, second],
+                        },
+                    };
+                    best = instants.into_iter()
+                        .filter(|&time| time > now)
+                        .chain(best)
+                        .min();
+
+                    if let Some(best) = best {
+                        if zone.earliest_instant(local + Duration::seconds(1)) > best {
+                            return Ok(best);
+                        }
+                    }
+                }
             }
         }
     }
@@ -164,3 +594,68 @@ fn worst_case_search(b: &mut test::Bencher) {
         let _ = black_box(out);
     })
 }
+
+#[cfg(test)]
+#[test]
+fn dst_transitions() {
+    // The rules of America/New_York as a POSIX TZ string, so the test doesn't depend on the tzdata
+    // of the host
+    let zone = Zone::named("EST5EDT,M3.2.0,M11.1.0").unwrap();
+    let at = |hour, minute: Vec<u32>| Time::On {
+        second: vec![0],
+        minute,
+        hour: vec![hour],
+        weekday: vec![],
+        day: vec![],
+        month: vec![],
+    };
+    let time = |rfc3339| DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc);
+    let next = |on: &Time, gap, repeat, now| on.next_run_after(time(now), &zone, Dst { gap, repeat }).unwrap();
+
+    // On 2021-03-14 clocks go from 02:00 EST to 03:00 EDT, 02:30 doesn't exist
+    let on = at(2, vec![30]);
+    assert_eq!(next(&on, DstGap::Skip, DstRepeat::First, "2021-03-14T00:00:00-05:00"), time("2021-03-15T02:30:00-04:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-03-14T00:00:00-05:00"), time("2021-03-14T03:00:00-04:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-03-14T03:00:00-04:00"), time("2021-03-15T02:30:00-04:00"));
+    // Multiple skipped times run only once
+    let on = at(2, vec![0, 15, 30, 45]);
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-03-14T01:59:00-05:00"), time("2021-03-14T03:00:00-04:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-03-14T03:00:00-04:00"), time("2021-03-15T02:00:00-04:00"));
+
+    // On 2021-11-07 clocks go from 02:00 EDT back to 01:00 EST, 01:30 happens twice
+    let on = at(1, vec![30]);
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-11-07T00:00:00-04:00"), time("2021-11-07T01:30:00-04:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-11-07T01:30:00-04:00"), time("2021-11-08T01:30:00-05:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::Both, "2021-11-07T01:30:00-04:00"), time("2021-11-07T01:30:00-05:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::Both, "2021-11-07T01:30:00-05:00"), time("2021-11-08T01:30:00-05:00"));
+    // Times before the current local time repeat as well
+    let on = at(1, (0..60).collect());
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::Both, "2021-11-07T01:59:00-04:00"), time("2021-11-07T01:00:00-05:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-11-07T01:59:00-04:00"), time("2021-11-08T01:00:00-05:00"));
+    assert_eq!(next(&on, DstGap::Next, DstRepeat::First, "2021-11-07T01:10:00-05:00"), time("2021-11-08T01:00:00-05:00"));
+}
+
+#[cfg(test)]
+#[test]
+fn retry_backoff() {
+    use crate::config::Config;
+
+    let task = |retry: &str| {
+        let toml = format!("[[task]]\nname = \"t\"\ncmd = \"true\"\nretry = {}", retry);
+        Config::parse(&toml).unwrap().tasks.remove(0)
+    };
+
+    let backoff = task("{ attempts = 4, delay = { seconds = 10 }, backoff = 2.5 }");
+    assert_eq!(backoff.retry_delay(1), Some(Duration::seconds(10)));
+    assert_eq!(backoff.retry_delay(2), Some(Duration::seconds(25)));
+    assert_eq!(backoff.retry_delay(3), Some(Duration::milliseconds(62_500)));
+    // The last attempt isn't retried
+    assert_eq!(backoff.retry_delay(4), None);
+
+    let capped = task("{ attempts = 4, delay = { seconds = 10 }, backoff = 2.5, max_delay = { minutes = 1 } }");
+    assert_eq!(capped.retry_delay(2), Some(Duration::seconds(25)));
+    assert_eq!(capped.retry_delay(3), Some(Duration::minutes(1)));
+
+    let huge = task("{ attempts = 1000, delay = { seconds = 1 }, backoff = 10.0 }");
+    assert_eq!(huge.retry_delay(500), Some(Duration::seconds(i32::MAX as i64)));
+}
@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+use std::{env, fmt};
+
+// A time zone loaded from the IANA tzdata installed on the system.
+//
+// `on` timings are evaluated in local time of the task's zone and converted to UTC instants for
+// scheduling.
+#[derive(Clone, PartialEq)]
+pub struct Zone {
+    name: String,
+    tz: tz::TimeZone,
+}
+
+impl fmt::Debug for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Zone({:?})", self.name)
+    }
+}
+
+impl Zone {
+    // The system's time zone from `TZ` or `/etc/localtime`, falls back to UTC when neither is set.
+    pub fn local() -> Zone {
+        if let Ok(name) = env::var("TZ") {
+            if let Ok(zone) = Zone::named(&name) {
+                return zone;
+            }
+        }
+        match tz::TimeZone::local() {
+            Ok(tz) => Zone { name: String::from("localtime"), tz },
+            Err(_) => Zone { name: String::from("UTC"), tz: tz::TimeZone::utc() },
+        }
+    }
+
+    // Loads a zone by its name in the IANA database, like `Europe/Prague`.
+    pub fn named(name: &str) -> Result<Zone> {
+        let tz = tz::TimeZone::from_posix_tz(name)
+            .map_err(|e| anyhow!("cannot load time zone `{}`: {}", name, e))?;
+        Ok(Zone { name: name.to_owned(), tz })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Offset from UTC in seconds at the instant `timestamp`.
+    fn offset(&self, timestamp: i64) -> i64 {
+        self.tz
+            .find_local_time_type(timestamp)
+            .map_or(0, |local_time_type| local_time_type.ut_offset().into())
+    }
+
+    pub fn to_local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        time.naive_utc() + Duration::seconds(self.offset(time.timestamp()))
+    }
+
+    pub fn to_fixed(&self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        let offset = FixedOffset::east_opt(self.offset(time.timestamp()) as i32).unwrap();
+        time.with_timezone(&offset)
+    }
+
+    // Finds the instants at which the local time is `local`.  There is none when the time is
+    // skipped by a transition (like DST starting), and two when the time repeats (like DST ending).
+    pub fn to_utc(&self, local: NaiveDateTime) -> LocalResult<DateTime<Utc>> {
+        let timestamp = Utc.from_utc_datetime(&local).timestamp();
+
+        // Offsets in effect around the time, transitions are never closer than a day apart
+        let mut offsets = [self.offset(timestamp - 86400), self.offset(timestamp + 86400)];
+        offsets.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut instants = offsets.iter()
+            .map(|offset| timestamp - offset)
+            .filter(|&instant| local == self.to_local(Utc.timestamp_opt(instant, 0).unwrap()))
+            .collect::<Vec<_>>();
+        instants.dedup();
+
+        match instants[..] {
+            [] => LocalResult::None,
+            [instant] => LocalResult::Single(Utc.timestamp_opt(instant, 0).unwrap()),
+            [earliest, latest] => LocalResult::Ambiguous(
+                Utc.timestamp_opt(earliest, 0).unwrap(),
+                Utc.timestamp_opt(latest, 0).unwrap(),
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    // Finds the instant at which a gap in local time, which `local` falls into, ends.
+    pub fn gap_end(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let timestamp = Utc.from_utc_datetime(&local).timestamp();
+        let before = self.offset(timestamp - 86400);
+        let after = self.offset(timestamp + 86400);
+
+        // Binary search for the transition, `low` is always before it and `high` after
+        let (mut low, mut high) = (timestamp - after, timestamp - before);
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            if self.offset(middle) == before {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        Utc.timestamp_opt(high, 0).unwrap()
+    }
+
+    // The earliest local time which may still occur after the instant `time`.  That's the current
+    // local time, unless the clocks are set back around `time` and the local times repeat.
+    pub fn earliest_local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        let timestamp = time.timestamp();
+        let offset = self.offset(timestamp - 86400).min(self.offset(timestamp + 86400));
+        time.naive_utc() + Duration::seconds(offset)
+    }
+
+    // The earliest instant at which the local time could be `local` or later.
+    pub fn earliest_instant(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        let timestamp = Utc.from_utc_datetime(&local).timestamp();
+        let offset = self.offset(timestamp - 86400).max(self.offset(timestamp + 86400));
+        Utc.timestamp_opt(timestamp - offset, 0).unwrap()
+    }
+
+    // Formats the instant in local time with the zone's abbreviation, like `2021-01-31 13:30:00 CET`.
+    pub fn format(&self, time: DateTime<Utc>) -> String {
+        let abbreviation = self.tz
+            .find_local_time_type(time.timestamp())
+            .map_or("UTC", |local_time_type| local_time_type.time_zone_designation());
+        format!("{} {}", self.to_local(time).format("%Y-%m-%d %H:%M:%S"), abbreviation)
+    }
+}